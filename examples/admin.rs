@@ -0,0 +1,86 @@
+use {
+    lazy_atomic::{admin::AdminServer, AtomicNmt},
+    serde_json::{json, Value},
+    std::{
+        env, fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        path::Path,
+        process,
+    },
+};
+
+/// This example starts the admin endpoint and changes a value through it.
+///
+/// Run with `--features admin`.
+fn main() {
+    let limit = AtomicNmt::builder()
+        .name("rate.limit")
+        .serializable()
+        .build(100u32);
+    let path = env::temp_dir().join(format!("lazy-atomic-admin-{}.sock", process::id()));
+    let server = AdminServer::new(&path).start().unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let stream = UnixStream::connect(&path).unwrap();
+    let request = |r| request(&stream, r);
+
+    let list = request("list");
+    assert_eq!(list["ok"][0]["name"], "rate.limit");
+    assert_eq!(request("get rate.limit"), json!({ "ok": 100 }));
+    assert_eq!(
+        request("set rate.limit 250"),
+        json!({ "ok": { "version": 1 } })
+    );
+    assert_eq!(limit.get(), 250);
+    assert!(request("set rate.limit -1")["error"].is_string());
+    assert!(request("get missing")["error"].is_string());
+    assert_eq!(request("inspect rate.limit")["ok"]["version"], 1);
+    assert!(request("stats")["ok"]["total"]["sets"].is_u64());
+    assert!(request("frobnicate")["error"].is_string());
+
+    drop(server);
+    assert!(!path.exists());
+    if unsafe { libc::geteuid() } == 0 {
+        check_peer_credentials(&path);
+    }
+    println!("ok");
+}
+
+/// Checks that other users cannot use the endpoint even if the socket is writable for them.
+fn check_peer_credentials(path: &Path) {
+    let _server = AdminServer::new(path).mode(0o666).start().unwrap();
+    match unsafe { libc::fork() } {
+        -1 => panic!("Could not fork"),
+        0 => {
+            // Become `nobody`.
+            let ok = unsafe { libc::setuid(65534) } == 0 && {
+                let stream = UnixStream::connect(path).unwrap();
+                // NOTE: The server responds without reading the request and closes the
+                // connection. Writing the request might fail.
+                let _ = writeln!(&stream, "list");
+                let mut response = String::new();
+                BufReader::new(&stream).read_line(&mut response).unwrap();
+                serde_json::from_str::<Value>(&response).unwrap()
+                    == json!({ "error": "permission denied" })
+            };
+            unsafe { libc::_exit(!ok as i32) }
+        }
+        pid => {
+            let mut status = 0;
+            unsafe {
+                libc::waitpid(pid, &mut status, 0);
+            }
+            assert_eq!(status, 0, "The request of another user was not rejected");
+        }
+    }
+}
+
+fn request(stream: &UnixStream, request: &str) -> Value {
+    writeln!(&*stream, "{}", request).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    println!("> {}\n< {}", request, response.trim());
+    serde_json::from_str(&response).unwrap()
+}
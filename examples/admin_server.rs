@@ -0,0 +1,33 @@
+use {
+    lazy_atomic::{
+        admin::{default_socket_path, AdminServer},
+        AtomicNmt,
+    },
+    std::{collections::BTreeMap, process, thread, time::Duration},
+};
+
+/// This example serves a few named atomics on the admin socket until it is killed.
+///
+/// Run with `--features admin` and try `lazy-atomic-ctl --pid <pid> list`.
+fn main() {
+    let _server = AdminServer::new(default_socket_path(process::id()))
+        .start()
+        .unwrap();
+    let _limit = AtomicNmt::builder()
+        .name("rate.limit")
+        .serializable()
+        .build(100u32);
+    let _routes = AtomicNmt::builder()
+        .name("routing.table")
+        .serializable()
+        .build(BTreeMap::from([("eu".to_string(), 1u32)]));
+    let ticks = AtomicNmt::builder()
+        .name("ticks")
+        .serializable()
+        .build(0u64);
+    println!("pid {}", process::id());
+    for i in 1.. {
+        thread::sleep(Duration::from_secs(1));
+        ticks.set(i);
+    }
+}
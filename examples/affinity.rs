@@ -0,0 +1,34 @@
+use {
+    lazy_atomic::{run_on_cpu, stats::num_helper_affinity_lost},
+    std::{sync::mpsc, time::Duration},
+};
+
+/// This example moves the per-cpu thread of cpu 1 to cpu 0 and checks that the thread moves
+/// itself back before running the next task.
+fn main() {
+    if num_cpus() < 2 {
+        println!("This example requires at least 2 cpus");
+        return;
+    }
+    let run = |task: Box<dyn FnOnce() -> i64 + Send>| {
+        let (tx, rx) = mpsc::channel();
+        run_on_cpu(1, Box::new(move || tx.send(task()).unwrap()));
+        rx.recv_timeout(Duration::from_secs(1))
+            .expect("The task did not run")
+    };
+    let tid = run(Box::new(|| unsafe { libc::syscall(libc::SYS_gettid) }));
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::CPU_SET(0, &mut set);
+        let res = libc::sched_setaffinity(tid as _, std::mem::size_of_val(&set), &set);
+        assert_eq!(res, 0, "Could not change the affinity");
+    }
+    let cpu = run(Box::new(|| unsafe { libc::sched_getcpu() as i64 }));
+    assert_eq!(cpu, 1);
+    assert_eq!(num_helper_affinity_lost(), 1);
+    println!("ok");
+}
+
+fn num_cpus() -> usize {
+    std::thread::available_parallelism().unwrap().get()
+}
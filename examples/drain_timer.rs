@@ -0,0 +1,45 @@
+use {
+    lazy_atomic::{stats::live_per_cpu_objects, AtomicNmt, Indexing},
+    std::{
+        sync::Barrier,
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// This example drops atomics whose copies are owned by cpus and concurrency ids that are not
+/// used again and checks that the deferred releases are performed by the timer.
+///
+/// Run with `--features debug-refcount`.
+fn main() {
+    for indexing in [Indexing::Cpu, Indexing::ConcurrencyId] {
+        let atomic = AtomicNmt::builder().indexing(indexing).build(vec![0]);
+        let threads = thread::available_parallelism().unwrap().get().min(8);
+        let barrier = Barrier::new(threads);
+        // NOTE: The threads wait for each other so that they run on different cpus and have
+        // different concurrency ids.
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    barrier.wait();
+                    assert_eq!(atomic.get(), [0]);
+                    barrier.wait();
+                });
+            }
+        });
+        // Dropping the atomic defers the releases of the copies of the other cpus.
+        drop(atomic);
+        let start = Instant::now();
+        while live_per_cpu_objects() > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{:?}: {} releases were not performed",
+                indexing,
+                live_per_cpu_objects()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        println!("{:?}: drained after {:?}", indexing, start.elapsed());
+    }
+    println!("ok");
+}
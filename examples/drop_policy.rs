@@ -0,0 +1,51 @@
+use {
+    lazy_atomic::{
+        stats::{num_background_drops, num_custom_drops},
+        AtomicNmt, DropPolicy, DropThread,
+    },
+    std::{sync::mpsc, thread, time::Duration},
+};
+
+/// A value with an expensive destructor. Reports the thread that drops it.
+#[derive(Clone)]
+struct Heavy(mpsc::Sender<String>);
+
+impl Drop for Heavy {
+    fn drop(&mut self) {
+        let thread = thread::current().name().unwrap_or("<unnamed>").to_string();
+        let _ = self.0.send(thread);
+    }
+}
+
+/// This example shows how old values can be dropped on a dedicated thread instead of the
+/// reader that releases the last reference.
+fn main() {
+    let (tx, rx) = mpsc::channel();
+
+    let thread = DropThread::spawn("dropper").unwrap();
+    let atomic = AtomicNmt::builder()
+        .drop_policy(DropPolicy::Background(thread))
+        .build(Heavy(tx.clone()));
+    atomic.set(Heavy(tx.clone()));
+    // Installs the new value and releases the old value.
+    drop(atomic.get());
+    // The arguments of `build` and `set` and the value returned by `get` are dropped by the
+    // caller. Only the old value is dropped by the background thread.
+    let mut threads: Vec<_> = (0..4)
+        .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect();
+    threads.sort();
+    println!("background: values dropped on {:?}", threads);
+    assert_eq!(threads, ["dropper", "main", "main", "main"]);
+    assert_eq!(num_background_drops(), 1);
+
+    let atomic = AtomicNmt::builder()
+        .drop_policy(DropPolicy::custom(|value| {
+            println!("custom: leaking the old value");
+            std::mem::forget(value);
+        }))
+        .build(Heavy(tx.clone()));
+    atomic.set(Heavy(tx));
+    drop(atomic.get());
+    assert_eq!(num_custom_drops(), 1);
+}
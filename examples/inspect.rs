@@ -0,0 +1,42 @@
+use {
+    lazy_atomic::{stats, AtomicNmt, Reclamation},
+    std::time::Instant,
+};
+
+/// This example shows which version of the value each cpu uses.
+fn main() {
+    for reclamation in [Reclamation::Readers, Reclamation::Membarrier] {
+        let atomic = AtomicNmt::builder()
+            .name("config")
+            .reclamation(reclamation)
+            .heap_size(|v: &Vec<u8>| v.capacity())
+            .build(vec![0u8; 16]);
+        let before = stats::snapshot();
+        let report = atomic.inspect();
+        // The tasks that read the reference counts are not counted.
+        let tasks = stats::snapshot().diff(&before).total;
+        assert_eq!((tasks.tasks_queued, tasks.tasks_run), (0, 0));
+        assert_eq!(report.version, 0);
+        assert!(report.heap_bytes >= 16);
+
+        let set_at = Instant::now();
+        atomic.set(vec![1; 16]);
+        let report = atomic.inspect();
+        assert_eq!(report.version, 1);
+        assert!(report.since_last_set <= set_at.elapsed());
+        for cpu in report.per_cpu.iter() {
+            // Cpus that have not called `get` since the `set` still use the old value.
+            match cpu.pending {
+                true => assert_eq!(cpu.version, Some(0)),
+                false => assert_eq!(cpu.version, Some(1)),
+            }
+            // Only the instance holds a reference.
+            assert!(matches!(cpu.refcount, Some(1) | None));
+        }
+
+        assert_eq!(atomic.get()[0], 1);
+        println!("{:#?}", report);
+        // `{:#?}` prints the report of the current cpu without running tasks on the others.
+        println!("{:#?}", atomic);
+    }
+}
@@ -0,0 +1,60 @@
+use {
+    lazy_atomic::{current_numa_node, AtomicNmt},
+    std::{ptr, thread},
+};
+
+/// See linux/include/uapi/linux/mempolicy.h
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Returns the memory policy of the current thread.
+fn get_mempolicy() -> Option<(libc::c_int, u64)> {
+    let mut mode = 0;
+    let mut mask = [0u64; 17];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut mode as *mut libc::c_int,
+            mask.as_mut_ptr(),
+            mask.len() * 64,
+            ptr::null_mut::<u8>(),
+            0,
+        )
+    };
+    match res {
+        0 => Some((mode, mask[0])),
+        _ => None,
+    }
+}
+
+/// This example builds NUMA-local atomics and checks that the memory policy of the thread is
+/// restored afterwards. On systems with a single node, the copies are allocated like those of
+/// other atomics.
+fn main() {
+    let node = current_numa_node();
+    println!("running on node {:?}", node);
+    // Prefer the current node so that the policy differs from the default.
+    let mask = 1u64 << node.unwrap_or(0);
+    let res = unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_PREFERRED, &mask, 64) };
+    let policy = get_mempolicy();
+    match res {
+        0 => assert_eq!(policy, Some((MPOL_PREFERRED, mask))),
+        _ => println!("the kernel does not support NUMA"),
+    }
+
+    let atomic = AtomicNmt::builder().numa_local(true).build(vec![1u8; 64]);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(atomic.get(), [1; 64]));
+        }
+    });
+    atomic.set(vec![2; 64]);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(atomic.get(), [2; 64]));
+        }
+    });
+    assert_eq!(atomic.get(), [2; 64]);
+    assert_eq!(get_mempolicy(), policy);
+    drop(atomic);
+    println!("ok");
+}
@@ -0,0 +1,30 @@
+use {
+    lazy_atomic::{stats, AtomicNmt},
+    std::io,
+};
+
+/// This example prints the statistics of two named instances in the OpenMetrics text format.
+fn main() {
+    let config = AtomicNmt::builder()
+        .name("config")
+        .build(String::from("v0"));
+    let routes = AtomicNmt::builder()
+        .name("routes \"eu\"")
+        .build(vec![0u32; 1024]);
+    // Unnamed instances are only included in the global counters.
+    let _unnamed = AtomicNmt::new(0u64);
+    for i in 1..=10 {
+        config.set(format!("v{}", i));
+        assert_eq!(*config.get(), format!("v{}", i));
+    }
+    routes.set(vec![1; 1024]);
+    assert_eq!(routes.get()[0], 1);
+
+    let mut out = Vec::new();
+    stats::render_openmetrics(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("lazy_atomic_instance_version{instance=\"config\"} 10\n"));
+    assert!(out.contains("lazy_atomic_instance_version{instance=\"routes \\\"eu\\\"\"} 1\n"));
+    assert!(out.ends_with("# EOF\n"));
+    stats::render_openmetrics(&mut io::stdout().lock()).unwrap();
+}
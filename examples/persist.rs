@@ -0,0 +1,113 @@
+use {
+    lazy_atomic::{
+        persist::{PersistError, Persisted},
+        registry, AtomicNmt,
+    },
+    serde_json::json,
+    std::{collections::BTreeMap, env, fs, process, thread},
+};
+
+/// This example writes an atomic to a temporary directory, restores it, and checks that
+/// corrupt snapshots are rejected.
+///
+/// Run with `--features persist`.
+fn main() {
+    let dir = env::temp_dir().join(format!("lazy-atomic-persist-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("routes.snapshot");
+    let open = || {
+        let builder = AtomicNmt::builder().name("routing.table");
+        Persisted::with_builder(&path, &builder, BTreeMap::from([("eu".to_string(), 1u32)]))
+    };
+
+    // There is no snapshot yet.
+    let routes = open().unwrap();
+    assert_eq!(routes.version(), 0);
+    assert_eq!(routes.get()["eu"], 1);
+    assert!(!path.exists());
+
+    routes
+        .set(BTreeMap::from([
+            ("eu".to_string(), 2),
+            ("us".to_string(), 3),
+        ]))
+        .unwrap();
+    // Values set through the registry are persisted as well.
+    let entry = registry::find("routing.table").pop().unwrap();
+    entry.write(json!({ "eu": 4, "us": 3 })).unwrap();
+    assert_eq!(routes.version(), 2);
+    println!("{}", fs::read_to_string(&path).unwrap());
+    drop((entry, routes));
+
+    // Restart.
+    let routes = open().unwrap();
+    assert_eq!(routes.version(), 2);
+    assert_eq!(routes.get()["eu"], 4);
+    assert_eq!(registry::find("routing.table").pop().unwrap().version(), 2);
+    routes.set(BTreeMap::new()).unwrap();
+    assert_eq!(routes.version(), 3);
+    drop(routes);
+    let snapshot = fs::read(&path).unwrap();
+
+    // A partially written snapshot.
+    fs::write(&path, &snapshot[..snapshot.len() - 1]).unwrap();
+    assert!(matches!(
+        open(),
+        Err(PersistError::LengthMismatch {
+            expected: 2,
+            actual: 1
+        })
+    ));
+    // A modified snapshot.
+    let mut modified = snapshot.clone();
+    *modified.last_mut().unwrap() = b']';
+    fs::write(&path, &modified).unwrap();
+    assert!(matches!(open(), Err(PersistError::ChecksumMismatch)));
+    // Not a snapshot.
+    fs::write(&path, "{}").unwrap();
+    assert!(matches!(open(), Err(PersistError::InvalidHeader)));
+    // A snapshot of a different type.
+    fs::write(&path, &snapshot).unwrap();
+    let flags = Persisted::open(&path, vec![false]);
+    assert!(matches!(flags, Err(PersistError::Deserialize(_))));
+
+    // Concurrent sets are not dropped. Each of them writes its own snapshot.
+    let routes = open().unwrap();
+    let mut versions: Vec<_> = thread::scope(|s| {
+        let threads: Vec<_> = (0..4u32)
+            .map(|t| {
+                let routes = &routes;
+                s.spawn(move || {
+                    (0..25)
+                        .map(|i| routes.set(BTreeMap::from([("eu".to_string(), t * 100 + i)])))
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap()
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect()
+    });
+    versions.sort_unstable();
+    assert_eq!(versions, (4..104).collect::<Vec<_>>());
+    let last = routes.get();
+    drop(routes);
+    let routes = open().unwrap();
+    assert_eq!(routes.version(), 103);
+    assert_eq!(routes.get(), last);
+    // No temporary files are left behind.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    // Snapshots that cannot be written leave the value unchanged.
+    fs::remove_dir_all(&dir).unwrap();
+    let err = routes
+        .set(BTreeMap::from([("eu".to_string(), 5)]))
+        .unwrap_err();
+    println!("{}", err);
+    assert!(matches!(err, PersistError::Io(_)));
+    assert_eq!(routes.version(), 103);
+    assert_eq!(routes.get(), last);
+    println!("ok");
+}
@@ -0,0 +1,96 @@
+use {
+    lazy_atomic::{pin_current_thread, unpin_current_thread, AtomicNmt},
+    std::{
+        mem,
+        sync::atomic::{
+            AtomicBool, AtomicIsize,
+            Ordering::{Relaxed, SeqCst},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of `Value`s that are alive.
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+
+/// A value that detects reads after it has been dropped.
+struct Value(Box<u64>);
+
+impl Value {
+    fn new(id: u64) -> Self {
+        LIVE.fetch_add(1, SeqCst);
+        Self(Box::new(id))
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        Self::new(*self.0)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        assert_ne!(*self.0, u64::MAX, "value dropped twice");
+        *self.0 = u64::MAX;
+        LIVE.fetch_sub(1, SeqCst);
+    }
+}
+
+/// Restricts the current thread to cpu 0 without pinning it.
+fn move_to_cpu_0() {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::CPU_SET(0, &mut set);
+        let res = libc::sched_setaffinity(0, mem::size_of_val(&set), &set);
+        assert_eq!(res, 0);
+    }
+}
+
+/// This example runs a pinned and an unpinned reader on cpu 0 while a third thread sets the
+/// value. The readers preempt each other and install new values in between. Run with
+/// `--features debug-refcount` to detect reference count errors reliably.
+fn main() {
+    let atomic = AtomicNmt::new(Value::new(0));
+    let stop = AtomicBool::new(false);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    thread::scope(|s| {
+        let reader = |pinned: bool| {
+            let (atomic, stop) = (&atomic, &stop);
+            move || {
+                match pinned {
+                    true => pin_current_thread(0).unwrap(),
+                    false => move_to_cpu_0(),
+                }
+                let mut gets = 0u64;
+                let mut last = 0;
+                while !stop.load(Relaxed) {
+                    let value = atomic.get();
+                    assert_ne!(*value.0, u64::MAX, "read of a dropped value");
+                    last = last.max(*value.0);
+                    gets += 1;
+                }
+                unpin_current_thread();
+                println!("pinned: {}, {} gets, last value {}", pinned, gets, last);
+            }
+        };
+        s.spawn(reader(true));
+        s.spawn(reader(false));
+        s.spawn(|| {
+            let mut i = 1;
+            while Instant::now() < deadline {
+                atomic.set(Value::new(i));
+                i += 1;
+                if i % 64 == 0 {
+                    thread::yield_now();
+                }
+            }
+            stop.store(true, Relaxed);
+        });
+    });
+    assert!(*atomic.get().0 > 0);
+    drop(atomic);
+    assert_eq!(LIVE.load(SeqCst), 0, "values leaked");
+    println!("ok");
+}
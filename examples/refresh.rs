@@ -0,0 +1,120 @@
+use {
+    lazy_atomic::{reload::Refresher, AtomicNmt},
+    std::{
+        sync::{
+            atomic::{AtomicU32, Ordering::Relaxed},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// Waits up to 5 seconds for `f` to return true.
+fn wait_for(what: &str, f: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(5));
+    }
+    println!("{}", what);
+}
+
+/// This example refreshes atomics periodically and on `SIGHUP`.
+///
+/// Run with `--features reload`.
+fn main() {
+    // A value that is refreshed every 20ms.
+    let ticks = AtomicNmt::new(0);
+    let handle = ticks
+        .refresh_every(Duration::from_millis(20), {
+            let mut ticks = 0;
+            move || {
+                ticks += 1;
+                Ok::<_, String>(ticks)
+            }
+        })
+        .unwrap();
+    wait_for("refreshed", || ticks.get() >= 3);
+    assert!(handle.last_error().is_none());
+    assert!(handle.last_good_version() >= 3);
+    handle.stop();
+    let stopped = ticks.get();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(ticks.get(), stopped);
+
+    // Errors keep the old value and back off.
+    let attempts = Arc::new(Mutex::new(vec![]));
+    let address = AtomicNmt::new("10.0.0.1".to_string());
+    let handle = Refresher::new(Duration::from_millis(10))
+        .jitter(0.0)
+        .max_backoff(Duration::from_millis(80))
+        .start(&address, {
+            let attempts = attempts.clone();
+            move || {
+                attempts.lock().unwrap().push(Instant::now());
+                Err::<String, _>("lookup failed")
+            }
+        })
+        .unwrap();
+    wait_for("backed off", || attempts.lock().unwrap().len() >= 5);
+    drop(handle);
+    let attempts = attempts.lock().unwrap();
+    let gaps: Vec<_> = attempts.windows(2).map(|w| w[1] - w[0]).collect();
+    println!("gaps between attempts: {:?}", gaps);
+    // 20ms, 40ms, 80ms, 80ms
+    assert!(gaps[0] >= Duration::from_millis(20));
+    assert!(gaps[1] >= Duration::from_millis(40));
+    assert!(gaps[3] >= Duration::from_millis(80) && gaps[3] < Duration::from_millis(160));
+    assert_eq!(address.get(), "10.0.0.1");
+
+    // A panic is an error and does not stop the scheduler.
+    let flaky = AtomicNmt::new(0);
+    let calls = Arc::new(AtomicU32::new(0));
+    let handle = Refresher::new(Duration::from_millis(10))
+        .max_backoff(Duration::from_millis(10))
+        .start(&flaky, {
+            let calls = calls.clone();
+            move || match calls.fetch_add(1, Relaxed) {
+                0 => panic!("refresh panicked"),
+                n => Ok::<_, String>(n),
+            }
+        })
+        .unwrap();
+    wait_for("recovered from panic", || flaky.get() > 0);
+    drop(handle);
+
+    // Values that are reloaded on `SIGHUP`. Signals are coalesced, so wait for each reload.
+    let config = AtomicNmt::new(0);
+    let reloads = Arc::new(AtomicU32::new(0));
+    let handle = config
+        .reload_on_signal(libc::SIGHUP, {
+            let reloads = reloads.clone();
+            move || Ok::<_, String>(reloads.fetch_add(1, Relaxed) + 1)
+        })
+        .unwrap();
+    let other = AtomicNmt::new(false);
+    let other_handle = other
+        .reload_on_signal(libc::SIGHUP, || Ok::<_, String>(true))
+        .unwrap();
+    for i in 1..=3 {
+        unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+        wait_for("reloaded on SIGHUP", || config.get() == i);
+    }
+    assert!(other.get());
+    assert_eq!(handle.last_good_version(), 3);
+    drop((handle, other_handle));
+
+    // The handler stays installed. The signal is ignored.
+    unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(config.get(), 3);
+    assert!(reload_fails_for_invalid_signal());
+    println!("ok");
+}
+
+fn reload_fails_for_invalid_signal() -> bool {
+    AtomicNmt::new(0)
+        .reload_on_signal(libc::SIGKILL, || Ok::<_, String>(1))
+        .is_err()
+}
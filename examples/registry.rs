@@ -0,0 +1,73 @@
+use {
+    lazy_atomic::{
+        registry::{self, RegistryError},
+        AtomicNmt,
+    },
+    serde_json::json,
+    std::{collections::BTreeMap, thread},
+};
+
+/// This example discovers named atomics through the registry and updates them as JSON.
+///
+/// Run with `--features serde`.
+fn main() {
+    let routes = AtomicNmt::builder()
+        .name("routing.table")
+        .serializable()
+        .build(BTreeMap::from([("eu".to_string(), 1u32)]));
+    let flags = AtomicNmt::named("feature.flags", vec![false; 4]);
+    let _unnamed = AtomicNmt::new(0);
+
+    for entry in registry::entries() {
+        println!("{:?}", entry);
+    }
+    assert_eq!(registry::entries().len(), 2);
+
+    let entry = registry::find("routing.table").pop().unwrap();
+    assert!(entry.type_name().contains("BTreeMap"));
+    assert_eq!(entry.version(), 0);
+    assert_eq!(entry.read().unwrap(), json!({ "eu": 1 }));
+    entry.write(json!({ "eu": 2, "us": 3 })).unwrap();
+    assert_eq!(entry.version(), 1);
+    assert_eq!(routes.get()["us"], 3);
+    assert!(matches!(
+        entry.write(json!("not a map")),
+        Err(RegistryError::Deserialize(_))
+    ));
+    assert_eq!(entry.version(), 1);
+
+    // Writes that race with another write are dropped and report it. Every installed value has
+    // its own version.
+    let mut versions = thread::scope(|s| {
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let entry = &entry;
+                s.spawn(move || {
+                    (0..1000)
+                        .filter_map(|i| match entry.write(json!({ "eu": t, "us": i })) {
+                            Ok(version) => Some(version),
+                            Err(RegistryError::Superseded) => None,
+                            Err(e) => panic!("{}", e),
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let versions = writers.into_iter().flat_map(|w| w.join().unwrap());
+        versions.collect::<Vec<_>>()
+    });
+    versions.sort_unstable();
+    versions.dedup();
+    println!("{} of 4000 writes installed", versions.len());
+    assert_eq!(versions.len() as u64, entry.version() - 1);
+    assert_eq!(versions.last(), Some(&entry.version()));
+
+    let entry = registry::find("feature.flags").pop().unwrap();
+    assert!(matches!(entry.read(), Err(RegistryError::NotSerializable)));
+    drop(entry);
+
+    // The registry does not keep instances alive.
+    drop(flags);
+    assert!(registry::find("feature.flags").is_empty());
+    println!("ok");
+}
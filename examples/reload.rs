@@ -0,0 +1,121 @@
+use {
+    lazy_atomic::{
+        reload::{watch_file, FileWatcher, ReloadError},
+        AtomicNmt,
+    },
+    std::{
+        env, fs,
+        num::ParseIntError,
+        os::unix::fs::symlink,
+        path::Path,
+        process, thread,
+        time::{Duration, Instant},
+    },
+};
+
+fn parse(bytes: &[u8]) -> Result<u32, ParseIntError> {
+    String::from_utf8_lossy(bytes).trim().parse()
+}
+
+/// Waits up to 5 seconds for `f` to return true.
+fn wait_for(what: &str, f: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+    println!("{}", what);
+}
+
+/// Replaces the file at `path` the way most editors do.
+fn replace(path: &Path, contents: &str) {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).unwrap();
+    fs::rename(&tmp, path).unwrap();
+}
+
+/// This example keeps atomics in sync with files in a temporary directory.
+///
+/// Run with `--features reload`.
+fn main() {
+    let dir = env::temp_dir().join(format!("lazy-atomic-reload-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("limit");
+    assert!(matches!(watch_file(&path, parse), Err(ReloadError::Io(_))));
+    fs::write(&path, "x").unwrap();
+    assert!(matches!(
+        watch_file(&path, parse),
+        Err(ReloadError::Parse(_))
+    ));
+    fs::write(&path, "1").unwrap();
+    let limit = FileWatcher::new(&path)
+        .debounce(Duration::from_millis(20))
+        .start(&AtomicNmt::builder().name("limit"), parse)
+        .unwrap();
+    assert_eq!(limit.get(), 1);
+    assert_eq!(limit.last_good_version(), 0);
+
+    fs::write(&path, "2").unwrap();
+    wait_for("written in place", || limit.get() == 2);
+    replace(&path, "3");
+    wait_for("replaced", || limit.get() == 3);
+    assert_eq!(limit.last_good_version(), 2);
+    assert!(limit.last_error().is_none());
+
+    // Values that cannot be parsed are ignored.
+    fs::write(&path, "three").unwrap();
+    wait_for("parse error", || limit.last_error().is_some());
+    println!("{}", limit.last_error().unwrap());
+    assert_eq!(limit.get(), 3);
+    assert_eq!(limit.last_good_version(), 2);
+    fs::write(&path, "4").unwrap();
+    wait_for("recovered", || limit.get() == 4);
+    assert!(limit.last_error().is_none());
+
+    // Bursts of writes are debounced.
+    let version = limit.last_good_version();
+    let burst = FileWatcher::new(&path)
+        .debounce(Duration::from_millis(200))
+        .start(&AtomicNmt::builder(), parse)
+        .unwrap();
+    for i in 5..15 {
+        fs::write(&path, i.to_string()).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    wait_for("debounced", || burst.get() == 14);
+    assert_eq!(burst.last_good_version(), 1);
+    wait_for("not debounced", || limit.get() == 14);
+    assert!(limit.last_good_version() > version);
+
+    // A `ConfigMap` volume: `value` links to `..data/value` and `..data` links to a directory
+    // with the current files. Updates swap the `..data` link.
+    let volume = dir.join("volume");
+    fs::create_dir_all(volume.join("..2024_01")).unwrap();
+    fs::write(volume.join("..2024_01/value"), "10").unwrap();
+    symlink("..2024_01", volume.join("..data")).unwrap();
+    symlink("..data/value", volume.join("value")).unwrap();
+    let config = watch_file(volume.join("value"), parse).unwrap();
+    assert_eq!(config.get(), 10);
+    fs::create_dir_all(volume.join("..2024_02")).unwrap();
+    fs::write(volume.join("..2024_02/value"), "11").unwrap();
+    symlink("..2024_02", volume.join("..data_tmp")).unwrap();
+    fs::rename(volume.join("..data_tmp"), volume.join("..data")).unwrap();
+    fs::remove_dir_all(volume.join("..2024_01")).unwrap();
+    wait_for("symlink swapped", || config.get() == 11);
+    // The new target is watched as well.
+    fs::write(volume.join("..2024_02/value"), "12").unwrap();
+    wait_for("target written in place", || config.get() == 12);
+
+    // The atomic is no longer updated once the reloader is dropped.
+    let atomic = AtomicNmt::clone(&limit);
+    drop(limit);
+    fs::write(&path, "20").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(atomic.get(), 14);
+
+    drop((burst, config));
+    fs::remove_dir_all(&dir).unwrap();
+    println!("ok");
+}
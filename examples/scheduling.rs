@@ -0,0 +1,32 @@
+use {
+    lazy_atomic::{run_on_cpu, Config, Scheduling},
+    std::{sync::mpsc, time::Duration},
+};
+
+/// This example runs the helper threads with `SCHED_OTHER` and a nice value even though they
+/// are spawned by a thread with a real-time policy.
+fn main() {
+    let param = libc::sched_param { sched_priority: 1 };
+    match unsafe { libc::sched_setscheduler(0, libc::SCHED_RR, &param) } {
+        0 => println!("spawning the helper thread from a SCHED_RR thread"),
+        _ => println!("not allowed to use SCHED_RR, spawning from a SCHED_OTHER thread"),
+    }
+    Config::new()
+        .scheduling(Scheduling::Other { nice: 5 })
+        .install()
+        .unwrap();
+    let (tx, rx) = mpsc::channel();
+    run_on_cpu(
+        0,
+        Box::new(move || unsafe {
+            let tid = libc::syscall(libc::SYS_gettid);
+            let policy = libc::sched_getscheduler(0);
+            let nice = libc::getpriority(libc::PRIO_PROCESS, tid as _);
+            tx.send((policy, nice)).unwrap();
+        }),
+    );
+    let (policy, nice) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    println!("policy {}, nice {}", policy, nice);
+    assert_eq!((policy, nice), (libc::SCHED_OTHER, 5));
+    println!("ok");
+}
@@ -0,0 +1,40 @@
+use {
+    lazy_atomic::{stats, AtomicNmt},
+    std::{thread, time::Duration},
+};
+
+/// This example prints the rate of the events of an instance and of the whole process.
+fn main() {
+    let atomic = AtomicNmt::new(0u64);
+    {
+        let atomic = atomic.clone();
+        thread::spawn(move || {
+            for i in 0.. {
+                atomic.set(i);
+                thread::sleep(Duration::from_micros(100));
+            }
+        });
+    }
+    {
+        let atomic = atomic.clone();
+        thread::spawn(move || loop {
+            atomic.get();
+        });
+    }
+    let mut instance = atomic.stats();
+    let mut global = stats::snapshot();
+    for _ in 0..3 {
+        thread::sleep(Duration::from_secs(1));
+        let (new_instance, new_global) = (atomic.stats(), stats::snapshot());
+        println!("instance: {:?}", new_instance.diff(&instance).total);
+        println!("global:   {:?}", new_global.diff(&global).total);
+        for (cpu, counters) in new_instance.diff(&instance).per_cpu.iter().enumerate() {
+            println!(
+                "  cpu {}: {} sets, {} updates",
+                cpu, counters.sets, counters.updates
+            );
+        }
+        instance = new_instance;
+        global = new_global;
+    }
+}
@@ -0,0 +1,26 @@
+use lazy_atomic::{
+    topology::{Granularity, Topology},
+    AtomicNmt,
+};
+
+/// This example prints how cpus are mapped to shards. The sysfs root can be passed as the first
+/// argument to inspect a fake topology.
+fn main() {
+    let root = std::env::args().nth(1).unwrap_or_else(|| "/sys".to_owned());
+    let topology = Topology::from_sysfs(&root).unwrap();
+    println!("cpu  core  llc  node");
+    for cpu in 0..topology.num_cpus() {
+        println!(
+            "{:3}  {:4}  {:3}  {:4}",
+            cpu,
+            topology.shard(cpu, Granularity::Core),
+            topology.shard(cpu, Granularity::Llc),
+            topology.shard(cpu, Granularity::NumaNode),
+        );
+    }
+    let atomic = AtomicNmt::builder()
+        .granularity(Granularity::Llc)
+        .topology(topology.into())
+        .build(vec![0u8; 1 << 20]);
+    assert_eq!(atomic.get().len(), 1 << 20);
+}
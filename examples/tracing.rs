@@ -0,0 +1,30 @@
+use {
+    lazy_atomic::{prewarm, shutdown, AtomicNmt},
+    std::{hint::black_box, thread},
+    tracing::Level,
+};
+
+/// This example prints the events emitted by the crate while several threads call `set`
+/// concurrently.
+fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::DEBUG)
+        .init();
+    let atomic = AtomicNmt::builder().name("example").build(0u64);
+    prewarm();
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let atomic = atomic.clone();
+            thread::spawn(move || {
+                for j in 0..10_000 {
+                    atomic.set(i * 10_000 + j);
+                    black_box(atomic.get());
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    shutdown();
+}
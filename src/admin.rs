@@ -0,0 +1,311 @@
+//! A local admin endpoint for the [`registry`](crate::registry).
+//!
+//! [`AdminServer`] serves a line-oriented protocol on a Unix socket. Every request is a single
+//! line and every response is a single line containing a JSON object. Successful requests
+//! return `{"ok": <result>}`, failed requests return `{"error": "<message>"}`.
+//!
+//! | Request              | Result                                                      |
+//! |----------------------|-------------------------------------------------------------|
+//! | `list`               | The name, type, version and last set time of all instances. |
+//! | `get <name>`         | The value of the instance.                                  |
+//! | `set <name> <json>`  | Sets the value of the instance. Returns its version.        |
+//! | `inspect <name>`     | See [`AtomicNmt::inspect`](crate::AtomicNmt::inspect).      |
+//! | `stats`              | See [`stats`](crate::stats).                                |
+//!
+//! Names must not contain whitespace. `get` and `set` require the instance to be built with
+//! [`Builder::serializable`](crate::Builder::serializable).
+//!
+//! The `lazy-atomic-ctl` binary is a client for this protocol. It finds the socket of a
+//! process by its PID if the socket is at [`default_socket_path`].
+
+use {
+    crate::{
+        registry::{self, Entry},
+        stats::{self, Counters},
+        InstanceReport,
+    },
+    serde_json::{json, Value},
+    std::{
+        env, fs,
+        io::{self, BufRead, BufReader, Read, Write},
+        mem,
+        os::unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            io::{AsRawFd, RawFd},
+            net::{UnixListener, UnixStream},
+        },
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{
+                AtomicBool,
+                Ordering::{Acquire, Release},
+            },
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::UNIX_EPOCH,
+    },
+};
+
+/// The maximum length of a request.
+const MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// Returns the path at which `lazy-atomic-ctl --pid <pid>` looks for the socket of a process.
+///
+/// Use `AdminServer::new(default_socket_path(std::process::id()))` to serve at this path.
+pub fn default_socket_path(pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("lazy-atomic-{}.sock", pid))
+}
+
+/// A builder for the admin endpoint.
+#[derive(Clone, Debug)]
+pub struct AdminServer {
+    path: PathBuf,
+    mode: u32,
+    allowed_uids: Vec<u32>,
+}
+
+impl AdminServer {
+    /// Creates a server that listens on the socket at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: 0o600,
+            allowed_uids: vec![],
+        }
+    }
+
+    /// Sets the mode of the socket file.
+    ///
+    /// Connecting requires write permission. The default is `0o600`.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Allows peers running as `uid` to use the endpoint.
+    ///
+    /// The peer is identified with `SO_PEERCRED`. By default, only root and the effective uid
+    /// of this process are allowed.
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.allowed_uids.push(uid);
+        self
+    }
+
+    /// Binds the socket and serves requests on a background thread.
+    ///
+    /// An existing socket at the path is replaced. Other files are not.
+    pub fn start(mut self) -> io::Result<AdminHandle> {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(&self.path)?;
+            }
+        }
+        let listener = UnixListener::bind(&self.path)?;
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(self.mode))?;
+        self.allowed_uids.extend([0, unsafe { libc::geteuid() }]);
+        let listener_fd = listener.as_raw_fd();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let allowed_uids = Arc::new(mem::take(&mut self.allowed_uids));
+            thread::Builder::new()
+                .name("la-admin".to_string())
+                .spawn(move || accept(listener, &stop, allowed_uids))?
+        };
+        Ok(AdminHandle {
+            path: self.path,
+            listener_fd,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running admin endpoint. The endpoint is stopped and the socket is removed when this
+/// handle is dropped.
+#[derive(Debug)]
+pub struct AdminHandle {
+    path: PathBuf,
+    listener_fd: RawFd,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AdminHandle {
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AdminHandle {
+    /// Connections that have already been accepted are served until the peer closes them.
+    fn drop(&mut self) {
+        // NOTE: Shutting down the listener wakes up the thread. `stop` is set before so that
+        // the thread returns instead of retrying `accept` on the shut down listener. The
+        // listener is not closed before the thread sees `stop`.
+        self.stop.store(true, Release);
+        unsafe {
+            libc::shutdown(self.listener_fd, libc::SHUT_RDWR);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn accept(listener: UnixListener, stop: &AtomicBool, allowed_uids: Arc<Vec<u32>>) {
+    for stream in listener.incoming() {
+        if stop.load(Acquire) {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let allowed_uids = allowed_uids.clone();
+        let _ = thread::Builder::new()
+            .name("la-admin-conn".to_string())
+            .spawn(move || {
+                let _ = serve(stream, &allowed_uids);
+            });
+    }
+}
+
+/// Returns the uid of the process on the other end of `stream`.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn serve(stream: UnixStream, allowed_uids: &[u32]) -> io::Result<()> {
+    let mut writer = &stream;
+    if !allowed_uids.contains(&peer_uid(&stream)?) {
+        return writeln!(writer, "{}", json!({ "error": "permission denied" }));
+    }
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_REQUEST_LEN as u64 + 1)
+            .read_line(&mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if len > MAX_REQUEST_LEN {
+            return writeln!(writer, "{}", json!({ "error": "request too long" }));
+        }
+        let response = match handle(line.trim()) {
+            Ok(result) => json!({ "ok": result }),
+            Err(error) => json!({ "error": error }),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+}
+
+fn handle(request: &str) -> Result<Value, String> {
+    let (command, args) = request.split_once(' ').unwrap_or((request, ""));
+    let args = args.trim();
+    match command {
+        "list" => Ok(registry::entries().iter().map(list_entry).collect()),
+        "get" => find(args)?.read().map_err(|e| e.to_string()),
+        "set" => {
+            let (name, value) = args.split_once(' ').ok_or("usage: set <name> <json>")?;
+            let value = serde_json::from_str(value).map_err(|e| format!("invalid JSON: {}", e))?;
+            let entry = find(name)?;
+            let version = entry.write(value).map_err(|e| e.to_string())?;
+            Ok(json!({ "version": version }))
+        }
+        "inspect" => Ok(report(&find(args)?.inspect())),
+        "stats" => Ok(stats()),
+        _ => Err(format!("unknown command `{}`", command)),
+    }
+}
+
+/// Returns the only instance named `name`.
+fn find(name: &str) -> Result<Entry, String> {
+    let mut entries = registry::find(name);
+    match entries.len() {
+        0 => Err(format!("no instance is named `{}`", name)),
+        1 => Ok(entries.pop().unwrap()),
+        n => Err(format!("{} instances are named `{}`", n, name)),
+    }
+}
+
+fn list_entry(entry: &Entry) -> Value {
+    let last_set = entry
+        .last_set()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    json!({
+        "name": entry.name(),
+        "type": entry.type_name(),
+        "version": entry.version(),
+        "last_set": last_set.as_secs_f64(),
+    })
+}
+
+fn report(report: &InstanceReport) -> Value {
+    let per_cpu: Vec<_> = report
+        .per_cpu
+        .iter()
+        .map(|cpu| {
+            json!({
+                "version": cpu.version,
+                "age": cpu.age.map(|age| age.as_secs_f64()),
+                "pending": cpu.pending,
+                "refcount": cpu.refcount,
+            })
+        })
+        .collect();
+    json!({
+        "name": report.name,
+        "version": report.version,
+        "since_last_set": report.since_last_set.as_secs_f64(),
+        "heap_bytes": report.heap_bytes,
+        "per_cpu": per_cpu,
+    })
+}
+
+fn counters(counters: &Counters) -> Value {
+    json!({
+        "sets": counters.sets,
+        "sets_dropped": counters.sets_dropped,
+        "updates": counters.updates,
+        "frees": counters.frees,
+        "tasks_queued": counters.tasks_queued,
+        "tasks_run": counters.tasks_run,
+        "stale_reads": counters.stale_reads,
+    })
+}
+
+fn stats() -> Value {
+    let snapshot = stats::snapshot();
+    json!({
+        "total": counters(&snapshot.total),
+        "per_cpu": snapshot.per_cpu.iter().map(counters).collect::<Vec<_>>(),
+        "off_cpu_releases": stats::num_off_cpu_release(),
+        "helper_affinity_lost": stats::num_helper_affinity_lost(),
+        "drops": {
+            "inline": stats::num_inline_drops(),
+            "background": stats::num_background_drops(),
+            "custom": stats::num_custom_drops(),
+        },
+    })
+}
@@ -0,0 +1,289 @@
+//! A client for the admin endpoint of `lazy-atomic`. See `lazy_atomic::admin`.
+
+use {
+    lazy_atomic::admin::default_socket_path,
+    serde_json::{json, Value},
+    std::{
+        env,
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+        path::PathBuf,
+        process, thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+const USAGE: &str = "\
+Usage: lazy-atomic-ctl (--pid <pid> | --socket <path>) [--json] <command>
+
+Commands:
+    list                          List the named atomics
+    get <name>                    Print the value of an atomic
+    set <name> <json>             Set the value of an atomic
+    watch <name> [<interval ms>]  Print the value of an atomic whenever its version changes
+    stats                         Print the counters of the process
+    inspect <name>                Print the state of each cpu
+
+Options:
+    --pid <pid>      Connect to the socket of the process at the default path
+    --socket <path>  Connect to the socket at <path>
+    --json           Print JSON instead of tables";
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("lazy-atomic-ctl: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut socket = None;
+    let mut json = false;
+    let mut command = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pid" => {
+                let pid = args.next().ok_or(USAGE)?;
+                let pid = pid.parse().map_err(|_| format!("invalid pid `{}`", pid))?;
+                socket = Some(default_socket_path(pid));
+            }
+            "--socket" => socket = Some(PathBuf::from(args.next().ok_or(USAGE)?)),
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => command.push(arg),
+        }
+    }
+    let socket = socket.ok_or(USAGE)?;
+    // NOTE: The protocol separates the name from the value by a space.
+    if let Some(name) = command.get(1) {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!(
+                "invalid name `{}`: names cannot contain whitespace",
+                name
+            ));
+        }
+    }
+    let mut client = Client::connect(&socket)
+        .map_err(|e| format!("could not connect to {}: {}", socket.display(), e))?;
+    let command: Vec<_> = command.iter().map(|s| s.as_str()).collect();
+    match command.as_slice() {
+        ["list"] => {
+            let list = client.request("list")?;
+            match json {
+                true => print_json(&list),
+                false => print_list(&list),
+            }
+        }
+        ["get", name] => print_json(&client.request(&format!("get {}", name))?),
+        ["set", name, value] => {
+            // NOTE: Requests are single lines. Pretty-printed JSON is sent without newlines.
+            let value: Value =
+                serde_json::from_str(value).map_err(|e| format!("invalid JSON: {}", e))?;
+            let result = client.request(&format!("set {} {}", name, value))?;
+            match json {
+                true => print_json(&result),
+                false => println!("{}: version {}", name, result["version"]),
+            }
+        }
+        ["watch", name] => watch(&mut client, name, Duration::from_secs(1), json)?,
+        ["watch", name, interval] => {
+            let interval = interval
+                .parse()
+                .map_err(|_| format!("invalid interval `{}`", interval))?;
+            watch(&mut client, name, Duration::from_millis(interval), json)?
+        }
+        ["stats"] => {
+            let stats = client.request("stats")?;
+            match json {
+                true => print_json(&stats),
+                false => print_stats(&stats),
+            }
+        }
+        ["inspect", name] => {
+            let report = client.request(&format!("inspect {}", name))?;
+            match json {
+                true => print_json(&report),
+                false => print_report(&report),
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Client {
+    fn connect(path: &PathBuf) -> std::io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self { stream, reader })
+    }
+
+    /// Sends a request and returns the result.
+    fn request(&mut self, request: &str) -> Result<Value, String> {
+        let mut response = String::new();
+        writeln!(self.stream, "{}", request)
+            .and_then(|_| self.reader.read_line(&mut response))
+            .map_err(|e| format!("could not send the request: {}", e))?;
+        let mut response: Value = serde_json::from_str(&response)
+            .map_err(|e| format!("invalid response `{}`: {}", response.trim(), e))?;
+        match response.get("error") {
+            Some(error) => Err(error.as_str().unwrap_or_default().to_string()),
+            None => Ok(response["ok"].take()),
+        }
+    }
+}
+
+/// Polls the version of `name` and prints the value whenever it changes.
+fn watch(client: &mut Client, name: &str, interval: Duration, json: bool) -> Result<(), String> {
+    let mut last = None;
+    loop {
+        let list = client.request("list")?;
+        let entry = list
+            .as_array()
+            .and_then(|list| list.iter().find(|entry| entry["name"] == name))
+            .ok_or_else(|| format!("no instance is named `{}`", name))?;
+        let version = entry["version"].as_u64();
+        if version != last {
+            last = version;
+            // NOTE: Values that are not serializable can still be watched.
+            let value = client.request(&format!("get {}", name)).ok();
+            match json {
+                true => println!("{}", json!({ "version": version, "value": value })),
+                false => println!(
+                    "{} version {}: {}",
+                    name,
+                    entry["version"],
+                    value.map_or("-".to_string(), |value| value.to_string())
+                ),
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn print_json(value: &Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn print_list(list: &Value) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let rows = list.as_array().into_iter().flatten().map(|entry| {
+        vec![
+            text(&entry["name"]),
+            text(&entry["type"]),
+            text(&entry["version"]),
+            match entry["last_set"].as_f64() {
+                Some(last_set) => format!("{:.1}s ago", now - last_set),
+                None => "-".to_string(),
+            },
+        ]
+    });
+    print_table(&["NAME", "TYPE", "VERSION", "LAST SET"], rows);
+}
+
+fn print_stats(stats: &Value) {
+    const COUNTERS: [&str; 7] = [
+        "sets",
+        "sets_dropped",
+        "updates",
+        "frees",
+        "tasks_queued",
+        "tasks_run",
+        "stale_reads",
+    ];
+    let row = |cpu: String, counters: &Value| {
+        let mut row = vec![cpu];
+        row.extend(COUNTERS.iter().map(|counter| text(&counters[counter])));
+        row
+    };
+    let per_cpu = stats["per_cpu"].as_array().into_iter().flatten();
+    let rows = per_cpu
+        .enumerate()
+        .map(|(cpu, counters)| row(cpu.to_string(), counters))
+        .chain([row("total".to_string(), &stats["total"])]);
+    let mut header = vec!["CPU".to_string()];
+    header.extend(COUNTERS.iter().map(|counter| counter.to_uppercase()));
+    let header: Vec<_> = header.iter().map(|s| s.as_str()).collect();
+    print_table(&header, rows);
+    println!();
+    println!("off-cpu releases:      {}", stats["off_cpu_releases"]);
+    println!("helper affinity lost:  {}", stats["helper_affinity_lost"]);
+    for policy in ["inline", "background", "custom"] {
+        println!(
+            "{:<22} {}",
+            format!("{} drops:", policy),
+            stats["drops"][policy]
+        );
+    }
+}
+
+fn print_report(report: &Value) {
+    println!("name:            {}", text(&report["name"]));
+    println!("version:         {}", report["version"]);
+    println!("since last set:  {}", seconds(&report["since_last_set"]));
+    println!("heap bytes:      {}", report["heap_bytes"]);
+    println!();
+    let per_cpu = report["per_cpu"].as_array().into_iter().flatten();
+    let rows = per_cpu.enumerate().map(|(cpu, state)| {
+        vec![
+            cpu.to_string(),
+            text(&state["version"]),
+            seconds(&state["age"]),
+            match state["pending"].as_bool() {
+                Some(true) => "yes".to_string(),
+                _ => "no".to_string(),
+            },
+            text(&state["refcount"]),
+        ]
+    });
+    print_table(&["CPU", "VERSION", "AGE", "PENDING", "REFCOUNT"], rows);
+}
+
+/// Formats a JSON value for a table. Strings are printed without quotes.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn seconds(value: &Value) -> String {
+    match value.as_f64() {
+        Some(seconds) => format!("{:.3}s", seconds),
+        None => "-".to_string(),
+    }
+}
+
+fn print_table(header: &[&str], rows: impl Iterator<Item = Vec<String>>) {
+    let rows: Vec<_> = rows.collect();
+    let mut widths: Vec<_> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |row: &mut dyn Iterator<Item = &str>| {
+        let cells: Vec<_> = row
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    print_row(&mut header.iter().copied());
+    for row in &rows {
+        print_row(&mut row.iter().map(|s| s.as_str()));
+    }
+}
@@ -0,0 +1,251 @@
+use {
+    once_cell::sync::OnceCell,
+    std::{
+        any::Any,
+        error::Error,
+        fmt::{Debug, Display, Formatter},
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// The maximum length of a thread name on Linux.
+const MAX_THREAD_NAME_LEN: usize = 15;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Returns the installed configuration or installs the default configuration.
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// The scheduling policy of the per-cpu helper threads.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Scheduling {
+    /// Inherit the scheduling policy of the thread that spawns the helper thread.
+    #[default]
+    Inherit,
+    /// `SCHED_OTHER` with the given nice value.
+    Other { nice: i32 },
+    /// `SCHED_FIFO` with the given priority.
+    Fifo { priority: i32 },
+    /// `SCHED_RR` with the given priority.
+    RoundRobin { priority: i32 },
+}
+
+/// What happens if a task panics on a helper thread.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PanicPolicy {
+    /// Report the panic and keep running the thread.
+    #[default]
+    Continue,
+    /// Report the panic and abort the process.
+    Abort,
+}
+
+/// A hook that is called on a helper thread before it runs its first task.
+type ThreadStartHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// A hook that is called on a helper thread after a task panicked.
+type TaskPanicHook = Arc<dyn Fn(usize, &(dyn Any + Send)) + Send + Sync>;
+
+/// The runtime configuration of the crate.
+///
+/// The configuration must be installed with [`Config::install`] before the first helper
+/// thread is spawned. Otherwise the default configuration is used.
+#[derive(Clone)]
+pub struct Config {
+    pub(crate) name_prefix: String,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) scheduling: Scheduling,
+    pub(crate) on_thread_start: Option<ThreadStartHook>,
+    pub(crate) channel_bound: Option<usize>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) on_task_panic: Option<TaskPanicHook>,
+    pub(crate) panic_policy: PanicPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name_prefix: "la-per-cpu".to_string(),
+            stack_size: None,
+            scheduling: Default::default(),
+            on_thread_start: None,
+            channel_bound: None,
+            idle_timeout: None,
+            on_task_panic: None,
+            panic_policy: Default::default(),
+        }
+    }
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("name_prefix", &self.name_prefix)
+            .field("stack_size", &self.stack_size)
+            .field("scheduling", &self.scheduling)
+            .field("on_thread_start", &self.on_thread_start.is_some())
+            .field("channel_bound", &self.channel_bound)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("on_task_panic", &self.on_task_panic.is_some())
+            .field("panic_policy", &self.panic_policy)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the prefix of the names of the helper threads. The thread of cpu `N` is named
+    /// `<prefix> N`.
+    ///
+    /// Linux limits thread names to 15 bytes. The default is `la-per-cpu`.
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// Sets the stack size of the helper threads.
+    ///
+    /// The default is the default of `std::thread`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Sets the scheduling policy of the helper threads.
+    ///
+    /// Helper threads of cpus that run real-time threads should usually use a real-time policy
+    /// themselves. Otherwise they might never run. The default is [`Scheduling::Inherit`].
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = scheduling;
+        self
+    }
+
+    /// Sets a hook that is called on each helper thread before it runs its first task. The
+    /// argument is the cpu of the thread.
+    ///
+    /// This can be used to move the thread into a cgroup.
+    pub fn on_thread_start(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets the maximum number of tasks that can be queued for a helper thread.
+    ///
+    /// If the queue is full, new tasks are dropped. Releases that have been deferred to a cpu
+    /// are retried the next time a task is sent to its helper thread. The default is
+    /// unbounded.
+    pub fn channel_bound(mut self, bound: usize) -> Self {
+        self.channel_bound = Some(bound);
+        self
+    }
+
+    /// Sets the time after which an idle helper thread exits.
+    ///
+    /// The thread is spawned again when the next task has to run on its cpu. The default is
+    /// to never exit.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets a hook that is called on a helper thread after one of its tasks panicked. The
+    /// arguments are the cpu of the thread and the panic payload.
+    ///
+    /// Tasks drop the values that have been released. They panic if the `Drop` implementation
+    /// of a value panics.
+    pub fn on_task_panic(
+        mut self,
+        hook: impl Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_task_panic = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets what happens after a task panicked on a helper thread.
+    ///
+    /// The hook set with [`Config::on_task_panic`] is called in either case. The default is
+    /// [`PanicPolicy::Continue`].
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Installs this configuration.
+    ///
+    /// Fails if the configuration is invalid or if a configuration has already been installed.
+    /// The default configuration is installed implicitly when the first helper thread is
+    /// spawned.
+    pub fn install(self) -> Result<(), ConfigError> {
+        self.validate()?;
+        CONFIG.set(self).map_err(|_| ConfigError::AlreadyInstalled)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // NOTE: The name is `<prefix> <cpu>`. Leave room for 4 digits.
+        if self.name_prefix.len() + 5 > MAX_THREAD_NAME_LEN || self.name_prefix.contains('\0') {
+            return Err(ConfigError::InvalidNamePrefix);
+        }
+        if self.channel_bound == Some(0) {
+            return Err(ConfigError::InvalidChannelBound);
+        }
+        let (policy, priority) = match self.scheduling {
+            Scheduling::Inherit => return Ok(()),
+            Scheduling::Other { nice } => {
+                return match (-20..=19).contains(&nice) {
+                    true => Ok(()),
+                    false => Err(ConfigError::InvalidNice(nice)),
+                };
+            }
+            Scheduling::Fifo { priority } => (libc::SCHED_FIFO, priority),
+            Scheduling::RoundRobin { priority } => (libc::SCHED_RR, priority),
+        };
+        let (min, max) = unsafe {
+            (
+                libc::sched_get_priority_min(policy),
+                libc::sched_get_priority_max(policy),
+            )
+        };
+        match (min..=max).contains(&priority) {
+            true => Ok(()),
+            false => Err(ConfigError::InvalidPriority(priority)),
+        }
+    }
+}
+
+/// An error returned by [`Config::install`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    /// A configuration has already been installed or the default configuration is in use.
+    AlreadyInstalled,
+    /// The thread name prefix is longer than 10 bytes or contains a nul byte.
+    InvalidNamePrefix,
+    /// The channel bound is 0.
+    InvalidChannelBound,
+    /// The nice value is not in the range `-20..=19`.
+    InvalidNice(i32),
+    /// The priority is not valid for the scheduling policy.
+    InvalidPriority(i32),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::AlreadyInstalled => {
+                write!(f, "a configuration has already been installed")
+            }
+            ConfigError::InvalidNamePrefix => write!(f, "the thread name prefix is invalid"),
+            ConfigError::InvalidChannelBound => write!(f, "the channel bound must not be 0"),
+            ConfigError::InvalidNice(n) => write!(f, "invalid nice value {}", n),
+            ConfigError::InvalidPriority(p) => write!(f, "invalid priority {}", p),
+        }
+    }
+}
+
+impl Error for ConfigError {}
@@ -4,26 +4,37 @@
 //! [ec]: https://en.wikipedia.org/wiki/Eventual_consistency
 
 pub use {
-    nmt::{inner::per_cpu_thread::run_on_cpu, AtomicNmt},
+    config::{Config, ConfigError, PanicPolicy, Scheduling},
+    nmt::{
+        builder::Builder,
+        drop_policy::{DropPolicy, DropThread},
+        inner::{
+            numa::current_numa_node,
+            per_cpu_thread::{
+                prewarm, run_on_cpu, set_release_executor, shutdown, GcTask, HelperThreads,
+                ReleaseExecutor,
+            },
+            pinned::{pin_current_thread, unpin_current_thread},
+        },
+        inspect::{CpuReport, InstanceReport},
+        AtomicNmt, Indexing, Reclamation,
+    },
     slc::AtomicSlc,
+    topology::Granularity,
 };
 
+#[cfg(feature = "admin")]
+pub mod admin;
+mod config;
 mod nmt;
+#[cfg(feature = "persist")]
+pub mod persist;
+pub mod registry;
+#[cfg(feature = "reload")]
+pub mod reload;
 mod slc;
-
-/// Statistics
-pub mod stats {
-    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
-
-    pub(super) static NUM_OFF_CPU_RELEASE: AtomicUsize = AtomicUsize::new(0);
-
-    /// How often objects had to sent to a per-cpu thread to be released due to thread migration.
-    ///
-    /// This should usually happen in <0.1% of `get` calls.
-    pub fn num_off_cpu_release() -> u64 {
-        NUM_OFF_CPU_RELEASE.load(Relaxed) as _
-    }
-}
+pub mod stats;
+pub mod topology;
 
 pub fn set_priority(p: i32) {
     // return;
@@ -0,0 +1,221 @@
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+use {
+    crate::{
+        nmt::{
+            drop_policy::DropPolicy,
+            inner::{registry, Inner},
+            AtomicNmt, Indexing, Reclamation,
+        },
+        topology::{Granularity, Topology},
+    },
+    std::{
+        fmt::{Debug, Formatter},
+        io,
+        marker::PhantomData,
+        sync::{Arc, Weak},
+    },
+};
+
+/// Returns the heap memory owned by a value. See [`Builder::heap_size`].
+pub(crate) type HeapSizeFn<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+
+/// Called by `set` with the value and its version before the value is published. If this
+/// returns an error, the value is not set. Calls are serialized but do not block readers or
+/// sets of other instances. Used by `persist::Persisted`.
+pub(crate) type OnSetFn<T> = Arc<dyn Fn(&T, u64) -> io::Result<()> + Send + Sync>;
+
+/// Converts values from and to JSON. See [`Builder::serializable`].
+#[cfg(feature = "serde")]
+pub(crate) struct Codec<T> {
+    pub to_json: fn(&T) -> serde_json::Result<serde_json::Value>,
+    pub from_json: fn(serde_json::Value) -> serde_json::Result<T>,
+}
+
+#[cfg(feature = "serde")]
+impl<T> Clone for Codec<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> Copy for Codec<T> {}
+
+/// A builder for [`AtomicNmt`].
+pub struct Builder<T> {
+    pub(crate) indexing: Indexing,
+    pub(crate) granularity: Granularity,
+    pub(crate) topology: Option<Arc<Topology>>,
+    pub(crate) numa_local: bool,
+    pub(crate) reclamation: Reclamation,
+    pub(crate) drop_policy: DropPolicy,
+    pub(crate) name: Option<String>,
+    pub(crate) heap_size: Option<HeapSizeFn<T>>,
+    #[cfg(feature = "serde")]
+    pub(crate) codec: Option<Codec<T>>,
+    pub(crate) on_set: Option<OnSetFn<T>>,
+    /// The version of the value passed to `build`.
+    pub(crate) initial_version: u64,
+    _phantom: PhantomData<fn(T)>,
+}
+
+impl<T> Default for Builder<T> {
+    fn default() -> Self {
+        Self {
+            indexing: Default::default(),
+            granularity: Default::default(),
+            topology: None,
+            numa_local: false,
+            reclamation: Default::default(),
+            drop_policy: Default::default(),
+            name: None,
+            heap_size: None,
+            #[cfg(feature = "serde")]
+            codec: None,
+            on_set: None,
+            initial_version: 0,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Builder<T> {
+    fn clone(&self) -> Self {
+        Self {
+            indexing: self.indexing,
+            granularity: self.granularity,
+            topology: self.topology.clone(),
+            numa_local: self.numa_local,
+            reclamation: self.reclamation,
+            drop_policy: self.drop_policy.clone(),
+            name: self.name.clone(),
+            heap_size: self.heap_size.clone(),
+            #[cfg(feature = "serde")]
+            codec: self.codec,
+            on_set: self.on_set.clone(),
+            initial_version: self.initial_version,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Debug for Builder<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Builder")
+            .field("indexing", &self.indexing)
+            .field("granularity", &self.granularity)
+            .field("numa_local", &self.numa_local)
+            .field("reclamation", &self.reclamation)
+            .field("drop_policy", &self.drop_policy)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<T> Builder<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// Sets how the per-cpu copies of the value are indexed.
+    ///
+    /// The default is [`Indexing::Cpu`].
+    pub fn indexing(mut self, indexing: Indexing) -> Self {
+        self.indexing = indexing;
+        self
+    }
+
+    /// Sets how many cpus share one copy of the value.
+    ///
+    /// Each cpu still has its own reference to the copy. This only reduces the number of times
+    /// the value is cloned. The default is [`Granularity::Cpu`].
+    ///
+    /// This has no effect with [`Indexing::ConcurrencyId`].
+    pub fn granularity(mut self, granularity: Granularity) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// Sets the topology used to map cpus to shards.
+    ///
+    /// The default is the topology of the system as described by `/sys`.
+    pub fn topology(mut self, topology: Arc<Topology>) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    /// Sets whether the copies of a cpu should be allocated on the NUMA node of the cpu.
+    ///
+    /// This changes the memory policy of the thread calling `set` while it clones the value.
+    /// Only memory that the allocator requests from the kernel during the clone is affected.
+    /// Small values are therefore often allocated from memory that is already mapped on
+    /// another node.
+    ///
+    /// This has no effect on systems with a single NUMA node and with
+    /// [`Indexing::ConcurrencyId`]. The default is `false`.
+    pub fn numa_local(mut self, numa_local: bool) -> Self {
+        self.numa_local = numa_local;
+        self
+    }
+
+    /// Sets how `set` installs new values and reclaims old values.
+    ///
+    /// The default is [`Reclamation::Readers`].
+    pub fn reclamation(mut self, reclamation: Reclamation) -> Self {
+        self.reclamation = reclamation;
+        self
+    }
+
+    /// Sets where old values are dropped.
+    ///
+    /// The default is [`DropPolicy::Inline`].
+    pub fn drop_policy(mut self, drop_policy: DropPolicy) -> Self {
+        self.drop_policy = drop_policy;
+        self
+    }
+
+    /// Sets the name of the instance.
+    ///
+    /// Named instances are added to the [`registry`](crate::registry) and are included in
+    /// [`stats::render_openmetrics`](crate::stats::render_openmetrics) with the name as the
+    /// `instance` label. Instances with the same name are reported together. By default,
+    /// instances are not named.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets a function that returns the heap memory owned by a value, excluding
+    /// `size_of::<T>()`.
+    ///
+    /// This is only used by [`AtomicNmt::inspect`]. By default, the memory owned by values is
+    /// not reported.
+    pub fn heap_size(mut self, heap_size: impl Fn(&T) -> usize + Send + Sync + 'static) -> Self {
+        self.heap_size = Some(Arc::new(heap_size));
+        self
+    }
+
+    /// Allows the value of a named instance to be read and written through
+    /// [`registry::Entry`](crate::registry::Entry) as JSON.
+    #[cfg(feature = "serde")]
+    pub fn serializable(mut self) -> Self
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.codec = Some(Codec {
+            to_json: |value| serde_json::to_value(value),
+            from_json: serde_json::from_value,
+        });
+        self
+    }
+
+    /// Creates the `Atomic<T>`.
+    pub fn build(&self, value: T) -> AtomicNmt<T> {
+        let inner = Arc::new(Inner::new(value, self));
+        if self.name.is_some() {
+            let weak: Weak<dyn registry::Instance> = Arc::downgrade(&inner) as _;
+            registry::register(weak);
+        }
+        AtomicNmt { inner }
+    }
+}
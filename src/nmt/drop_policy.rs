@@ -0,0 +1,157 @@
+use {
+    crate::stats::{NUM_BACKGROUND_DROPS, NUM_CUSTOM_DROPS, NUM_INLINE_DROPS},
+    flume::Sender,
+    std::{
+        any::Any,
+        fmt::{Debug, Formatter},
+        io,
+        mem::ManuallyDrop,
+        ops::Deref,
+        panic::{self, AssertUnwindSafe},
+        process,
+        sync::{atomic::Ordering::Relaxed, Arc},
+        thread,
+    },
+};
+
+/// Where the old values of an [`AtomicNmt`](crate::AtomicNmt) are dropped.
+///
+/// Old values are dropped by whichever thread releases the last reference to them. This can be
+/// a reader, a writer, or a per-cpu thread. If `T::drop` is expensive, it can be moved to
+/// another thread.
+#[derive(Clone, Default)]
+pub enum DropPolicy {
+    /// Drop the value on the thread that releases the last reference.
+    #[default]
+    Inline,
+    /// Send the value to a thread that drops it.
+    Background(DropThread),
+    /// Pass the value to a function.
+    Custom(Arc<dyn Fn(Box<dyn Any + Send>) + Send + Sync>),
+}
+
+impl Debug for DropPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropPolicy::Inline => f.write_str("Inline"),
+            DropPolicy::Background(thread) => f.debug_tuple("Background").field(thread).finish(),
+            DropPolicy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl DropPolicy {
+    /// Creates a [`DropPolicy::Custom`].
+    pub fn custom(f: impl Fn(Box<dyn Any + Send>) + Send + Sync + 'static) -> Self {
+        DropPolicy::Custom(Arc::new(f))
+    }
+
+    fn drop_value<T: Send + 'static>(&self, value: T) {
+        match self {
+            DropPolicy::Inline => {
+                NUM_INLINE_DROPS.fetch_add(1, Relaxed);
+                drop(value);
+            }
+            DropPolicy::Background(thread) => {
+                NUM_BACKGROUND_DROPS.fetch_add(1, Relaxed);
+                thread.drop_value(Box::new(value));
+            }
+            DropPolicy::Custom(f) => {
+                NUM_CUSTOM_DROPS.fetch_add(1, Relaxed);
+                f(Box::new(value));
+            }
+        }
+    }
+}
+
+/// A thread that drops values. Used with [`DropPolicy::Background`].
+///
+/// The thread exits when all clones of this object and all values sent to it have been
+/// dropped.
+///
+/// The thread does not exist in the child of a `fork`. Values are dropped inline there.
+#[derive(Debug)]
+pub struct DropThread {
+    sender: ManuallyDrop<Sender<Box<dyn Any + Send>>>,
+    /// The process that spawned the thread.
+    pid: u32,
+}
+
+impl DropThread {
+    /// Spawns a new thread with the given name.
+    pub fn spawn(name: impl Into<String>) -> io::Result<Self> {
+        let (sender, receiver) = flume::unbounded::<Box<dyn Any + Send>>();
+        thread::Builder::new().name(name.into()).spawn(move || {
+            while let Ok(value) = receiver.recv() {
+                // NOTE: The panic is reported by the panic hook. Keep the thread alive.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(value)));
+            }
+        })?;
+        Ok(Self {
+            sender: ManuallyDrop::new(sender),
+            pid: process::id(),
+        })
+    }
+
+    fn drop_value(&self, value: Box<dyn Any + Send>) {
+        // NOTE: In the child of a `fork`, the thread might have held the lock of the channel.
+        if self.pid != process::id() {
+            drop(value);
+            return;
+        }
+        if let Err(e) = self.sender.send(value) {
+            drop(e.into_inner());
+        }
+    }
+}
+
+impl Clone for DropThread {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            pid: self.pid,
+        }
+    }
+}
+
+impl Drop for DropThread {
+    fn drop(&mut self) {
+        // NOTE: See `drop_value`. The sender is leaked in the child of a `fork`.
+        if self.pid == process::id() {
+            unsafe { ManuallyDrop::drop(&mut self.sender) };
+        }
+    }
+}
+
+/// A copy of a value that is dropped according to a `DropPolicy`.
+pub(crate) struct Shared<T: Send> {
+    value: ManuallyDrop<T>,
+    policy: DropPolicy,
+    /// `DropPolicy::drop_value::<T>`. Stored here because it requires `T: 'static`.
+    drop_value: fn(&DropPolicy, T),
+}
+
+impl<T: Send + 'static> Shared<T> {
+    pub(crate) fn new(value: T, policy: &DropPolicy) -> Self {
+        Self {
+            value: ManuallyDrop::new(value),
+            policy: policy.clone(),
+            drop_value: DropPolicy::drop_value::<T>,
+        }
+    }
+}
+
+impl<T: Send> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T: Send> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        (self.drop_value)(&self.policy, value);
+    }
+}
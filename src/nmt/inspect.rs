@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+/// The state of an [`AtomicNmt`](crate::AtomicNmt) at a point in time.
+///
+/// See [`AtomicNmt::inspect`](crate::AtomicNmt::inspect).
+#[derive(Clone, Debug)]
+pub struct InstanceReport {
+    /// The name given with [`Builder::name`](crate::Builder::name).
+    pub name: Option<String>,
+    /// The version of the last value that was set. The value passed to `build` has version 0
+    /// and every `set` that installs its value increments the version.
+    pub version: u64,
+    /// The time since the last value was set.
+    pub since_last_set: Duration,
+    /// The state of each cpu, indexed by cpu. With
+    /// [`Indexing::ConcurrencyId`](crate::Indexing::ConcurrencyId), this is indexed by
+    /// concurrency id instead.
+    pub per_cpu: Box<[CpuReport]>,
+    /// An estimate of the heap memory used by the instance.
+    ///
+    /// This includes the memory owned by the values only if a function has been given with
+    /// [`Builder::heap_size`](crate::Builder::heap_size), and only for the copies whose
+    /// reference count could be read.
+    pub heap_bytes: usize,
+}
+
+/// The state of the copy of a single cpu.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CpuReport {
+    /// The version of the value returned by `get` on this cpu. `None` if the cpu does not have
+    /// a copy yet.
+    pub version: Option<u64>,
+    /// The time since the value returned by `get` on this cpu was set.
+    pub age: Option<Duration>,
+    /// Whether a newer value has been set that the next `get` on this cpu will install.
+    pub pending: bool,
+    /// The number of references to the copy, including the one held by the instance itself.
+    ///
+    /// The reference count can only be read on the cpu that owns the copy. It is read by the
+    /// per-cpu thread of the cpu and is `None` if that thread did not respond in time, if the
+    /// copy is indexed by concurrency id or if the cpu does not have a copy.
+    pub refcount: Option<u64>,
+}
@@ -1,7 +1,18 @@
+pub mod builder;
+pub mod drop_policy;
+pub mod inspect;
 pub mod versioning;
 
+#[cfg(feature = "reload")]
 use {
-    crate::nmt::versioning::VersioningNone,
+    crate::reload::{self, RefreshHandle, Refresher},
+    std::{io, time::Duration},
+};
+use {
+    crate::{
+        nmt::{builder::Builder, inspect::InstanceReport, versioning::VersioningNone},
+        stats::Snapshot,
+    },
     cfg_if::cfg_if,
     inner::Inner,
     std::{
@@ -22,6 +33,47 @@ cfg_if! {
     }
 }
 
+/// How the per-cpu copies of a value are indexed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Indexing {
+    /// One copy per cpu.
+    ///
+    /// All copies are allocated up front.
+    #[default]
+    Cpu,
+    /// One copy per concurrency id (`mm_cid`).
+    ///
+    /// The concurrency ids of a process are bounded by the number of its threads and the number
+    /// of cpus it is allowed to run on. Copies are allocated the first time a concurrency id
+    /// accesses the value. A process with 4 threads will therefore usually allocate no more
+    /// than 4 copies, regardless of the number of cpus in the system.
+    ///
+    /// This requires Linux 6.3 or later. On older kernels, this falls back to [`Indexing::Cpu`].
+    ConcurrencyId,
+}
+
+/// How `set` installs new values and reclaims old values.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Reclamation {
+    /// `set` stores the new value in a per-cpu slot. The next `get` on each cpu installs it
+    /// and releases the old value.
+    ///
+    /// Old values stay alive until a reader on their cpu shows up.
+    #[default]
+    Readers,
+    /// `set` installs the new values itself, restarts all in-flight `get` calls with
+    /// `membarrier`, and then reclaims the old values on the calling thread.
+    ///
+    /// This makes `set` more expensive but `get` never has to install new values and old values
+    /// are freed immediately unless a reader is still using them. Threads pinned with
+    /// [`pin_current_thread`](crate::pin_current_thread) do not use the pinned fast path for such
+    /// atomics.
+    ///
+    /// This requires Linux 5.10 or later. On older kernels, this falls back to
+    /// [`Reclamation::Readers`].
+    Membarrier,
+}
+
 /// An atomic variable with eventual consistency.
 ///
 /// This type supports arbitrary `T: Clone + Send + Sync + 'static`.
@@ -38,7 +90,7 @@ cfg_if! {
 ///
 /// On all other targets, this type falls back to `Arc<Mutex<T>>` which will be very slow.
 pub struct AtomicNmt<T: Send + Sync> {
-    inner: Arc<Inner<VersioningNone, T>>,
+    pub(crate) inner: Arc<Inner<VersioningNone, T>>,
 }
 
 impl<T> AtomicNmt<T>
@@ -47,15 +99,33 @@ where
 {
     /// Creates a new `Atomic<T>`.
     pub fn new(value: T) -> Self {
-        Self {
-            inner: Arc::new(Inner::new(value)),
-        }
+        Self::builder().build(value)
+    }
+
+    /// Creates a new `Atomic<T>` and adds it to the [`registry`](crate::registry) under `name`.
+    ///
+    /// This is a shorthand for `AtomicNmt::builder().name(name).build(value)`. The instance is
+    /// not serializable: reading or writing its value through its
+    /// [`registry::Entry`](crate::registry::Entry) or the admin socket fails with
+    /// `RegistryError::NotSerializable`. Use
+    /// `AtomicNmt::builder().name(name).serializable().build(value)` for values that implement
+    /// `Serialize` and `DeserializeOwned`.
+    pub fn named(name: impl Into<String>, value: T) -> Self {
+        Self::builder().name(name).build(value)
+    }
+
+    /// Returns a builder for an `Atomic<T>` with non-default options.
+    pub fn builder() -> Builder<T> {
+        Builder::default()
     }
 
     /// Sets the value.
     ///
     /// At some point after this call, all calls to `get` will return this value or a value set by
     /// a later call to `set`.
+    ///
+    /// If the atomic is persisted and the snapshot cannot be written, the value is not set. Use
+    /// [`Persisted::set`](crate::persist::Persisted::set) to observe the error.
     #[inline]
     pub fn set(&self, value: T) {
         self.inner.set(value);
@@ -80,6 +150,57 @@ where
     pub fn get(&self) -> T {
         self.inner.get().value
     }
+
+    /// Returns the counters of this instance.
+    ///
+    /// See [`stats`](crate::stats) for details.
+    pub fn stats(&self) -> Snapshot {
+        self.inner.counters.snapshot()
+    }
+
+    /// Returns the version, reference count and age of the copy of each cpu.
+    ///
+    /// This is meant for debugging, for example to find cpus that still use an old value long
+    /// after a `set`. It does not install pending values. Reading the reference counts
+    /// requires running a task on the per-cpu thread of every cpu and waits up to 100ms for
+    /// them.
+    ///
+    /// `{:#?}` prints a report that only contains the reference count of the current cpu and
+    /// does not run any tasks.
+    pub fn inspect(&self) -> InstanceReport {
+        self.inner.inspect()
+    }
+
+    /// Sets the value to the result of `refresh` every `interval` on a background thread.
+    ///
+    /// This is a shorthand for `Refresher::new(interval).start(self, refresh)`. See
+    /// [`Refresher`] for jitter and backoff after errors.
+    #[cfg(feature = "reload")]
+    pub fn refresh_every<E>(
+        &self,
+        interval: Duration,
+        refresh: impl FnMut() -> Result<T, E> + Send + 'static,
+    ) -> io::Result<RefreshHandle<E>>
+    where
+        E: Send + Sync + 'static,
+    {
+        Refresher::new(interval).start(self, refresh)
+    }
+
+    /// Sets the value to the result of `reload` whenever the process receives `signal`.
+    ///
+    /// See [`reload::reload_on_signal`].
+    #[cfg(feature = "reload")]
+    pub fn reload_on_signal<E>(
+        &self,
+        signal: i32,
+        reload: impl FnMut() -> Result<T, E> + Send + 'static,
+    ) -> io::Result<RefreshHandle<E>>
+    where
+        E: Send + Sync + 'static,
+    {
+        reload::reload_on_signal(self, signal, reload)
+    }
 }
 
 impl<T: Send + Sync> Clone for AtomicNmt<T> {
@@ -95,8 +216,14 @@ where
     T: Debug + Clone + Send + Sync + 'static,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Atomic")
-            .field("value", &self.get())
-            .finish()
+        // NOTE: `{:#?}` also prints the state of each cpu. Unlike `inspect`, this does not run
+        // tasks on other cpus and only reads the reference count of the current cpu.
+        let alternate = f.alternate();
+        let mut f = f.debug_struct("Atomic");
+        f.field("value", &self.get());
+        if alternate {
+            f.field("report", &self.inner.report_current_cpu());
+        }
+        f.finish()
     }
 }
@@ -1,8 +1,13 @@
+use std::io::{self, Write};
+
 /// Object that aborts the process when it is dropped. Usually because of panic=unwind.
-pub struct AbortOnDrop;
+///
+/// The message is printed before aborting.
+pub struct AbortOnDrop(pub &'static str);
 
 impl Drop for AbortOnDrop {
     fn drop(&mut self) {
+        let _ = writeln!(io::stderr(), "lazy-atomic: {}. Aborting.", self.0);
         std::process::abort();
     }
 }
@@ -0,0 +1,116 @@
+//! The counters of `stats::Snapshot`.
+
+#[cfg(feature = "stats")]
+use {
+    crate::nmt::inner::cache_line::CacheLineAligned,
+    std::sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+use {
+    crate::{
+        nmt::inner::num_cpus::NUM_CPUS,
+        stats::{Counters, Snapshot},
+    },
+    once_cell::sync::Lazy,
+};
+
+/// An event that is counted.
+#[cfg_attr(not(feature = "stats"), allow(dead_code))]
+#[derive(Copy, Clone, Debug)]
+pub enum Event {
+    Set,
+    SetDropped,
+    Update,
+    Free,
+    TaskQueued,
+    TaskRun,
+    StaleRead,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct AtomicCounters([AtomicU64; 7]);
+
+/// Counters for each cpu.
+pub struct PerCpuCounters {
+    #[cfg(feature = "stats")]
+    by_cpu: Box<[CacheLineAligned<AtomicCounters>]>,
+}
+
+impl Default for PerCpuCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The counters of all instances combined.
+pub static GLOBAL: Lazy<PerCpuCounters> = Lazy::new(PerCpuCounters::new);
+
+impl PerCpuCounters {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "stats")]
+            by_cpu: std::iter::repeat_with(Default::default)
+                .take(*NUM_CPUS)
+                .collect(),
+        }
+    }
+
+    /// Counts an event of this instance on `cpu`. The event is also counted globally.
+    #[inline]
+    pub fn inc(&self, cpu: usize, event: Event) {
+        #[cfg(feature = "stats")]
+        {
+            self.inc_local(cpu, event);
+            GLOBAL.inc_local(cpu, event);
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = (cpu, event);
+    }
+
+    /// Counts an event on `cpu` without counting it globally.
+    #[cfg(feature = "stats")]
+    #[inline]
+    fn inc_local(&self, cpu: usize, event: Event) {
+        if let Some(counters) = self.by_cpu.get(cpu) {
+            counters.0 .0[event as usize].fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        #[cfg(feature = "stats")]
+        {
+            let per_cpu: Box<[Counters]> = self
+                .by_cpu
+                .iter()
+                .map(|counters| {
+                    let get = |event: Event| counters.0 .0[event as usize].load(Relaxed);
+                    Counters {
+                        sets: get(Event::Set),
+                        sets_dropped: get(Event::SetDropped),
+                        updates: get(Event::Update),
+                        frees: get(Event::Free),
+                        tasks_queued: get(Event::TaskQueued),
+                        tasks_run: get(Event::TaskRun),
+                        stale_reads: get(Event::StaleRead),
+                    }
+                })
+                .collect();
+            Snapshot {
+                total: per_cpu.iter().fold(Counters::default(), |a, b| a + *b),
+                per_cpu,
+            }
+        }
+        #[cfg(not(feature = "stats"))]
+        Snapshot {
+            total: Counters::default(),
+            per_cpu: vec![Counters::default(); *NUM_CPUS].into(),
+        }
+    }
+}
+
+/// Counts an event that does not belong to an instance.
+#[cfg(feature = "stats")]
+#[inline]
+pub fn inc_global(cpu: usize, event: Event) {
+    GLOBAL.inc_local(cpu, event);
+}
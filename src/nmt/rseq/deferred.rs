@@ -0,0 +1,356 @@
+use {
+    crate::nmt::{
+        inner::{
+            cache_line::CacheLineAligned,
+            fork,
+            num_cpus::NUM_CPUS,
+            per_cpu_thread::{cpu_mask, run_on_cpu, sched_setaffinity},
+            rseq::{get_rseq, rseq},
+        },
+        Indexing,
+    },
+    flume::{Receiver, Sender},
+    once_cell::sync::Lazy,
+    parking_lot::Mutex,
+    std::{
+        io, mem, ptr,
+        sync::atomic::{
+            AtomicBool, AtomicPtr, AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// If this many releases are deferred to a cpu, the per-cpu thread of that cpu is woken up to
+/// perform them without waiting for the timer.
+const DRAIN_THRESHOLD: usize = 64;
+
+/// Releases that are deferred to a cpu or concurrency id that is not used again are performed
+/// by a timer after this delay.
+const DRAIN_DELAY: Duration = Duration::from_millis(100);
+
+/// Performs a deferred release. Returns `false` if the release has to be deferred again
+/// because the thread is not running on the owner of the data.
+pub type ReleaseFn = unsafe fn(rseq: *mut rseq, indexing: Indexing, data: *mut ()) -> bool;
+
+/// A release that could not be performed because the thread was not running on the owner of
+/// the data.
+struct Deferred {
+    next: *mut Deferred,
+    data: *mut (),
+    release: ReleaseFn,
+}
+
+/// A lock-free stack of deferred releases.
+///
+/// Any thread can push to the stack. The stack is drained by threads running on the owner of
+/// the stack.
+#[derive(Default)]
+struct DeferredQueue {
+    head: AtomicPtr<Deferred>,
+    /// The number of releases in the stack.
+    depth: AtomicUsize,
+    /// Whether a task that drains the stack has been sent to the per-cpu thread.
+    drain_requested: AtomicBool,
+    /// Whether the timer will drain the stack.
+    drain_scheduled: AtomicBool,
+}
+
+type Queues = Lazy<Box<[CacheLineAligned<DeferredQueue>]>>;
+
+static BY_CPU: Queues = Lazy::new(|| {
+    std::iter::repeat_with(Default::default)
+        .take(*NUM_CPUS)
+        .collect()
+});
+
+static BY_CID: Queues = Lazy::new(|| {
+    std::iter::repeat_with(Default::default)
+        .take(*NUM_CPUS)
+        .collect()
+});
+
+fn queues(indexing: Indexing) -> &'static [CacheLineAligned<DeferredQueue>] {
+    match indexing {
+        Indexing::Cpu => &BY_CPU,
+        Indexing::ConcurrencyId => &BY_CID,
+    }
+}
+
+unsafe fn push_node(queue: &DeferredQueue, node: *mut Deferred) {
+    let mut head = queue.head.load(Relaxed);
+    loop {
+        (*node).next = head;
+        match queue
+            .head
+            .compare_exchange_weak(head, node, Release, Relaxed)
+        {
+            Ok(_) => return,
+            Err(h) => head = h,
+        }
+    }
+}
+
+/// Defers a release until a thread runs on the owner `index`.
+pub fn push(indexing: Indexing, index: u32, data: *mut (), release: ReleaseFn) {
+    let node = Box::into_raw(Box::new(Deferred {
+        next: ptr::null_mut(),
+        data,
+        release,
+    }));
+    let queue = &queues(indexing)[index as usize].0;
+    let depth = queue.depth.fetch_add(1, Relaxed) + 1;
+    unsafe {
+        push_node(queue, node);
+    }
+    // There is no way to run code with a specific concurrency id.
+    if indexing == Indexing::Cpu && depth >= DRAIN_THRESHOLD {
+        request_drain(index);
+    }
+    if !queue.drain_scheduled.swap(true, Relaxed) {
+        schedule_drain(indexing, index);
+    }
+}
+
+/// Sends a task that drains the queue of `cpu` to the per-cpu thread unless such a task has
+/// already been sent.
+fn request_drain(cpu: u32) {
+    if BY_CPU[cpu as usize].0.drain_requested.swap(true, Relaxed) {
+        return;
+    }
+    let request = DrainRequest { cpu, ran: false };
+    run_on_cpu(
+        cpu as usize,
+        Box::new(move || {
+            let mut request = request;
+            let cpu = request.cpu;
+            request.ran = true;
+            drop(request);
+            unsafe {
+                drain_slow(get_rseq(), Indexing::Cpu, &BY_CPU[cpu as usize].0);
+            }
+        }),
+    );
+}
+
+/// Resets `drain_requested` when the task that drains the queue of `cpu` runs or is dropped
+/// without running.
+///
+/// If the executor drops the task, e.g. because its queue is full, the timer is armed again so
+/// that the releases are retried.
+struct DrainRequest {
+    cpu: u32,
+    ran: bool,
+}
+
+impl Drop for DrainRequest {
+    fn drop(&mut self) {
+        let queue = &BY_CPU[self.cpu as usize].0;
+        queue.drain_requested.store(false, Relaxed);
+        if !self.ran && queue.depth.load(Relaxed) > 0 && !queue.drain_scheduled.swap(true, Relaxed)
+        {
+            schedule_drain(Indexing::Cpu, self.cpu);
+        }
+    }
+}
+
+/// Forgets the drain tasks that have been requested and the timer. Called in the child of a
+/// `fork`.
+///
+/// # Safety
+///
+/// `lock_timer` must have been called before the `fork`.
+pub unsafe fn reset_drain_requests() {
+    for queues in [&BY_CPU, &BY_CID] {
+        if let Some(queues) = Lazy::get(queues) {
+            for queue in queues.iter() {
+                queue.0.drain_requested.store(false, Relaxed);
+                queue.0.drain_scheduled.store(false, Relaxed);
+            }
+        }
+    }
+    // NOTE: The timer thread no longer exists and might have held the lock of the channel.
+    mem::forget((*TIMER.data_ptr()).take());
+    fork::unlock_in_child(&TIMER);
+}
+
+/// A drain of a queue that is due at `deadline`.
+struct Timer {
+    deadline: Instant,
+    indexing: Indexing,
+    index: u32,
+}
+
+/// Sends timers to the timer thread. The thread is spawned when the first release is deferred.
+static TIMER: Mutex<Option<Sender<Timer>>> = parking_lot::const_mutex(None);
+
+/// Locks the timer before a `fork`.
+pub fn lock_timer() {
+    mem::forget(TIMER.lock());
+}
+
+/// Unlocks the timer in the parent of a `fork`.
+///
+/// # Safety
+///
+/// `lock_timer` must have been called by this thread.
+pub unsafe fn unlock_timer() {
+    TIMER.force_unlock();
+}
+
+/// Drains the queue of `index` after `DRAIN_DELAY` if no reader has drained it by then.
+fn schedule_drain(indexing: Indexing, index: u32) {
+    let timer = Timer {
+        deadline: Instant::now() + DRAIN_DELAY,
+        indexing,
+        index,
+    };
+    let mut sender = TIMER.lock();
+    if sender.is_none() {
+        *sender = spawn_timer().ok();
+    }
+    let sent = match &*sender {
+        Some(sender) => sender.send(timer).is_ok(),
+        None => false,
+    };
+    if !sent {
+        queues(indexing)[index as usize]
+            .0
+            .drain_scheduled
+            .store(false, Relaxed);
+    }
+}
+
+fn spawn_timer() -> io::Result<Sender<Timer>> {
+    let (tx, rx) = flume::unbounded();
+    thread::Builder::new()
+        .name("la-drain-timer".to_string())
+        .spawn(move || timer_thread(rx))?;
+    Ok(tx)
+}
+
+/// Tries to drain a queue of a concurrency id on every cpu.
+///
+/// There is no way to run code with a specific concurrency id. However, the kernel remembers
+/// the id that was last used on a cpu and usually assigns it to the next thread of the process
+/// that runs there. Releases that do not belong to the id of this thread are put back.
+fn drain_by_cid(queue: &DeferredQueue) {
+    for cpu in 0..*NUM_CPUS {
+        if sched_setaffinity(0, &cpu_mask(cpu)).is_ok() {
+            unsafe { drain_slow(get_rseq(), Indexing::ConcurrencyId, queue) };
+        }
+        if queue.depth.load(Relaxed) == 0 {
+            break;
+        }
+    }
+    // NOTE: The kernel removes the cpus that are not in our cpuset.
+    let _ = sched_setaffinity(0, &vec![usize::MAX; *NUM_CPUS / usize::BITS as usize + 1]);
+}
+
+/// Tries to perform the releases that are deferred to concurrency ids. Changes the affinity
+/// of the current thread.
+pub fn drain_cids() {
+    if let Some(queues) = Lazy::get(&BY_CID) {
+        for queue in queues.iter() {
+            if queue.0.depth.load(Relaxed) > 0 {
+                drain_by_cid(&queue.0);
+            }
+        }
+    }
+}
+
+fn timer_thread(rx: Receiver<Timer>) {
+    // NOTE: All timers have the same delay. They therefore arrive in the order of their
+    // deadlines.
+    while let Ok(timer) = rx.recv() {
+        let now = Instant::now();
+        if timer.deadline > now {
+            thread::sleep(timer.deadline - now);
+        }
+        let queue = &queues(timer.indexing)[timer.index as usize].0;
+        queue.drain_scheduled.store(false, Relaxed);
+        if queue.depth.load(Relaxed) == 0 {
+            continue;
+        }
+        match timer.indexing {
+            Indexing::Cpu => request_drain(timer.index),
+            Indexing::ConcurrencyId => {
+                drain_by_cid(queue);
+                if queue.depth.load(Relaxed) > 0 && !queue.drain_scheduled.swap(true, Relaxed) {
+                    schedule_drain(Indexing::ConcurrencyId, timer.index);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the number of releases deferred to `index`.
+pub fn depth(indexing: Indexing, index: usize) -> usize {
+    queues(indexing)[index].0.depth.load(Relaxed)
+}
+
+/// Performs the deferred releases of `index` if there are any.
+///
+/// # Safety
+///
+/// `rseq` must be the rseq pointer of the current thread.
+#[inline]
+pub unsafe fn drain(rseq: *mut rseq, indexing: Indexing, index: u32) {
+    let queue = &queues(indexing).get_unchecked(index as usize).0;
+    if !queue.head.load(Relaxed).is_null() {
+        drain_slow(rseq, indexing, queue);
+    }
+}
+
+#[cold]
+#[inline(never)]
+unsafe fn drain_slow(rseq: *mut rseq, indexing: Indexing, queue: &DeferredQueue) {
+    let mut remaining = Remaining {
+        queue,
+        current: ptr::null_mut(),
+        next: queue.head.swap(ptr::null_mut(), Acquire),
+    };
+    while !remaining.next.is_null() {
+        let node = remaining.next;
+        remaining.current = node;
+        remaining.next = (*node).next;
+        if ((*node).release)(rseq, indexing, (*node).data) {
+            queue.depth.fetch_sub(1, Relaxed);
+            drop(Box::from_raw(node));
+        } else {
+            // We've been preempted and are now running on a different cpu. Put the release
+            // back.
+            push_node(queue, node);
+        }
+        remaining.current = ptr::null_mut();
+    }
+}
+
+/// Keeps the queue consistent if a release panics because the `Drop` implementation of a
+/// value panics.
+struct Remaining<'a> {
+    queue: &'a DeferredQueue,
+    /// The release that is currently being performed.
+    current: *mut Deferred,
+    /// The releases that have not been performed yet.
+    next: *mut Deferred,
+}
+
+impl Drop for Remaining<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.current.is_null() {
+                // The value has been released before the panic.
+                self.queue.depth.fetch_sub(1, Relaxed);
+                drop(Box::from_raw(self.current));
+            }
+            while !self.next.is_null() {
+                let node = self.next;
+                self.next = (*node).next;
+                push_node(self.queue, node);
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Keeps the global state consistent across `fork`.
+//!
+//! The child of a `fork` only contains the forking thread. Locks held by other threads stay
+//! locked forever and the helper threads no longer exist.
+
+#[cfg(feature = "debug-refcount")]
+use crate::nmt::inner::per_cpu_rc::debug;
+#[cfg(feature = "reload")]
+use crate::reload;
+use {
+    crate::nmt::inner::{deferred, numa, per_cpu_thread, registry},
+    once_cell::sync::Lazy,
+    parking_lot::{Mutex, RawMutex, RawRwLock, RwLock, RwLockReadGuard},
+    parking_lot_core::DEFAULT_UNPARK_TOKEN,
+    std::{
+        cell::Cell,
+        sync::{Arc, Weak},
+    },
+};
+
+/// Held for reading while a lock of an instance is held. Held for writing during `fork`.
+static FORK_LOCK: RwLock<()> = parking_lot::const_rwlock(());
+
+static REGISTERED: Lazy<()> = Lazy::new(|| unsafe {
+    let res = libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+    assert_eq!(res, 0, "Could not register fork handlers");
+});
+
+/// Registers the fork handlers if they have not been registered yet.
+pub fn register() {
+    Lazy::force(&REGISTERED);
+}
+
+/// Prevents `fork` until the guard is dropped.
+///
+/// Must be held while holding the `set_lock` or `latest` lock of an instance.
+pub fn guard() -> Guard {
+    // NOTE: Values are cloned and dropped while the guard is held. This might call `set` of
+    // another instance. Only such nested guards skip a waiting `fork`. Otherwise a stream of
+    // readers could starve it.
+    let nested = DEPTH
+        .try_with(|d| d.replace(d.get() + 1) > 0)
+        .unwrap_or(true);
+    Guard {
+        _lock: match nested {
+            true => FORK_LOCK.read_recursive(),
+            false => FORK_LOCK.read(),
+        },
+    }
+}
+
+thread_local! {
+    /// The number of guards held by the current thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// See `guard`.
+pub struct Guard {
+    _lock: RwLockReadGuard<'static, ()>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let _ = DEPTH.try_with(|d| d.set(d.get().saturating_sub(1)));
+    }
+}
+
+/// The locks returned by `slow_lock`.
+static SLOW_LOCKS: Mutex<Vec<Weak<Mutex<()>>>> = parking_lot::const_mutex(Vec::new());
+
+/// Returns a lock for slow operations like I/O.
+///
+/// Unlike `guard`, the lock does not delay `fork`. In the child, it is unlocked if a thread of
+/// the parent held it.
+pub fn slow_lock() -> Arc<Mutex<()>> {
+    let lock = Arc::new(Mutex::new(()));
+    let mut locks = SLOW_LOCKS.lock();
+    locks.retain(|lock| lock.strong_count() > 0);
+    locks.push(Arc::downgrade(&lock));
+    lock
+}
+
+/// Unlocks `mutex` in the child of a `fork`.
+///
+/// # Safety
+///
+/// `mutex` must have been locked by this thread before the `fork`.
+pub unsafe fn unlock_in_child<T: ?Sized>(mutex: &Mutex<T>) {
+    // NOTE: The threads that were waiting for the lock in the parent are still queued but do
+    // not exist in the child. A fair unlock would hand the lock to one of them.
+    let key = mutex.raw() as *const RawMutex as usize;
+    parking_lot_core::unpark_all(key, DEFAULT_UNPARK_TOKEN);
+    mutex.force_unlock();
+}
+
+extern "C" fn prepare() {
+    std::mem::forget(FORK_LOCK.write());
+    std::mem::forget(SLOW_LOCKS.lock());
+    per_cpu_thread::lock_threads();
+    deferred::lock_timer();
+    numa::lock();
+    registry::lock();
+    #[cfg(feature = "debug-refcount")]
+    debug::lock();
+    #[cfg(feature = "reload")]
+    reload::lock_scheduler();
+}
+
+extern "C" fn parent() {
+    unsafe {
+        #[cfg(feature = "reload")]
+        reload::unlock_scheduler(false);
+        #[cfg(feature = "debug-refcount")]
+        debug::unlock(false);
+        registry::unlock(false);
+        numa::unlock(false);
+        deferred::unlock_timer();
+        per_cpu_thread::unlock_threads(false);
+        SLOW_LOCKS.force_unlock();
+        FORK_LOCK.force_unlock_write();
+    }
+}
+
+extern "C" fn child() {
+    unsafe {
+        #[cfg(feature = "reload")]
+        reload::unlock_scheduler(true);
+        #[cfg(feature = "debug-refcount")]
+        debug::unlock(true);
+        registry::unlock(true);
+        numa::unlock(true);
+        per_cpu_thread::unlock_threads(true);
+        // The drain tasks that have been sent to the old helper threads and the timers will
+        // never run.
+        deferred::reset_drain_requests();
+        // NOTE: The threads that held the slow locks no longer exist.
+        for lock in (*SLOW_LOCKS.data_ptr()).iter().filter_map(Weak::upgrade) {
+            if lock.is_locked() {
+                unlock_in_child(&lock);
+            }
+        }
+        unlock_in_child(&SLOW_LOCKS);
+        // NOTE: Readers and writers are queued under the address of the lock and the address
+        // after it. See `unlock_in_child`.
+        let key = FORK_LOCK.raw() as *const RawRwLock as usize;
+        parking_lot_core::unpark_all(key, DEFAULT_UNPARK_TOKEN);
+        parking_lot_core::unpark_all(key + 1, DEFAULT_UNPARK_TOKEN);
+        FORK_LOCK.force_unlock_write();
+    }
+}
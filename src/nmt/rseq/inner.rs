@@ -1,33 +1,133 @@
+#[cfg(feature = "tracing")]
+use crate::nmt::inner::trace::Sampler;
+#[cfg(feature = "serde")]
+use crate::{nmt::builder::Codec, registry::RegistryError};
 use {
-    crate::nmt::{
-        inner::{
-            cache_line::CacheLineAligned,
-            num_cpus::NUM_CPUS,
-            per_cpu_rc::{self, PerCpuRc},
-            rseq::{self, get_rseq},
+    crate::{
+        nmt::{
+            builder::{Builder, HeapSizeFn, OnSetFn},
+            drop_policy::{DropPolicy, Shared},
+            inner::{
+                cache_line::CacheLineAligned,
+                counters::{Event, PerCpuCounters},
+                deferred, fork, membarrier,
+                num_cpus::NUM_CPUS,
+                numa::{self, NodeCpus, PreferNode},
+                per_cpu_rc::{self, PerCpuRc},
+                per_cpu_thread, pinned,
+                registry::Instance,
+                rseq::{self, get_rseq},
+            },
+            inspect::{CpuReport, InstanceReport},
+            versioning::{Versioned, Versioning},
+            Indexing, Reclamation,
         },
-        versioning::{Versioned, Versioning},
+        stats::Snapshot,
+        topology::{self, Granularity},
     },
     parking_lot::Mutex,
     std::{
-        iter,
+        any,
+        collections::HashSet,
+        io, iter, mem,
         ops::Deref,
         ptr,
         sync::{
             atomic::{
-                AtomicPtr,
-                Ordering::{AcqRel, Acquire, Relaxed},
+                AtomicPtr, AtomicU64,
+                Ordering::{AcqRel, Acquire, Relaxed, Release},
             },
             Arc,
         },
+        thread,
+        time::{Duration, Instant, SystemTime},
     },
 };
 
+/// A per-cpu reference to a value that is possibly shared with other cpus.
+type Rc<V, T> = PerCpuRc<Versioned<V, Stamped<Arc<Shared<T>>>>>;
+type Slot<V, T> = CacheLineAligned<AtomicPtr<Rc<V, T>>>;
+type Value<V, T> = *mut Rc<V, T>;
+
+/// The id of the next instance.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies the `set` that stored a value.
+#[derive(Copy, Clone, Default)]
+pub struct Stamp {
+    /// The value of `Inner::generation` after the `set`.
+    generation: u64,
+    /// The time of the `set` in nanoseconds since the instance was created.
+    set_at: u64,
+}
+
+/// A value and the `set` that stored it.
+pub struct Stamped<S> {
+    pub value: S,
+    pub stamp: Stamp,
+}
+
+impl<S> Deref for Stamped<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+/// The `Stamp` of the value that is used by a cpu.
+#[derive(Default)]
+pub struct Installed {
+    generation: AtomicU64,
+    set_at: AtomicU64,
+}
+
+/// The reference count of the copy of a cpu as read by its per-cpu thread.
+struct Probe {
+    cpu: usize,
+    refcount: u64,
+    /// Identifies the copy. Cpus in the same shard share a copy.
+    copy: usize,
+    heap_size: usize,
+}
+
 pub struct Inner<V: Versioning, T: Send + Sync> {
+    /// Identifies the instance in the reports of the `debug-refcount` feature.
+    pub id: u64,
+    pub counters: Arc<PerCpuCounters>,
+    /// The name used by `stats::render_openmetrics`.
+    pub name: Option<Box<str>>,
+    /// The number of values installed by `set`.
+    pub generation: AtomicU64,
+    pub created: Instant,
+    /// The time of the last `set` in nanoseconds since `created`.
+    pub last_set: AtomicU64,
+    pub heap_size: Option<HeapSizeFn<T>>,
+    #[cfg(feature = "serde")]
+    pub codec: Option<Codec<T>>,
+    /// The `on_set` callback and the lock that serializes its calls. See `try_set`.
+    pub on_set: Option<(OnSetFn<T>, Arc<Mutex<()>>)>,
     pub version: CacheLineAligned<V::AtomicVersion>,
     pub set_lock: CacheLineAligned<Mutex<()>>,
-    pub value_by_cpu: Box<[CacheLineAligned<AtomicPtr<PerCpuRc<Versioned<V, T>>>>]>,
-    pub new_value_by_cpu: Box<[CacheLineAligned<AtomicPtr<PerCpuRc<Versioned<V, T>>>>]>,
+    pub indexing: Indexing,
+    pub reclamation: Reclamation,
+    pub drop_policy: DropPolicy,
+    /// The last value that was set. Only used with `Indexing::ConcurrencyId` to populate
+    /// copies on first access. Protected by `set_lock` for writes.
+    pub latest: Mutex<Option<Versioned<V, Stamped<Shared<T>>>>>,
+    /// For each cpu, the lowest cpu that shares a copy of the value with it.
+    pub shards: Box<[usize]>,
+    /// The cpus grouped by the NUMA node their copies are allocated on.
+    pub nodes: Box<[NodeCpus]>,
+    /// The per-cpu references. With `Indexing::ConcurrencyId`, entries are null until the
+    /// concurrency id first accesses the value.
+    ///
+    /// Each cpu has its own reference count even if the value is shared with other cpus.
+    /// This way the reference count can be modified without atomic operations.
+    pub value_by_cpu: Box<[Slot<V, T>]>,
+    pub new_value_by_cpu: Box<[Slot<V, T>]>,
+    /// For each cpu, the `Stamp` of the value in `value_by_cpu`.
+    pub installed_by_cpu: Box<[CacheLineAligned<Installed>]>,
 }
 
 unsafe impl<V: Versioning, T: Send + Sync + 'static> Send for Inner<V, T> {}
@@ -38,80 +138,653 @@ where
     V: Versioning,
     T: Clone + Send + Sync + 'static,
 {
-    pub fn new(value: T) -> Self {
+    pub fn new(value: T, builder: &Builder<T>) -> Self {
         rseq::ensure_enabled();
-        let value = Versioned {
-            version: V::new(),
-            value: value.clone(),
+        fork::register();
+        let indexing = match builder.indexing {
+            Indexing::ConcurrencyId if !rseq::mm_cid_available() => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("concurrency ids are not available, falling back to Indexing::Cpu");
+                Indexing::Cpu
+            }
+            indexing => indexing,
         };
-        Self {
+        let reclamation = match builder.reclamation {
+            Reclamation::Membarrier if !membarrier::rseq_available() => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("membarrier is not available, falling back to Reclamation::Readers");
+                Reclamation::Readers
+            }
+            reclamation => reclamation,
+        };
+        // Concurrency ids are not tied to cpus. Every id gets its own copy.
+        let granularity = match indexing {
+            Indexing::Cpu => builder.granularity,
+            Indexing::ConcurrencyId => Granularity::Cpu,
+        };
+        let topology = builder.topology.as_ref().unwrap_or(&topology::SYSTEM);
+        let stamp = Stamp {
+            generation: builder.initial_version,
+            set_at: 0,
+        };
+        let mut slf = Self {
+            id: NEXT_ID.fetch_add(1, Relaxed),
+            counters: Default::default(),
+            name: builder.name.as_deref().map(Into::into),
+            generation: AtomicU64::new(stamp.generation),
+            created: Instant::now(),
+            last_set: AtomicU64::new(0),
+            heap_size: builder.heap_size.clone(),
+            #[cfg(feature = "serde")]
+            codec: builder.codec,
+            on_set: builder
+                .on_set
+                .clone()
+                .map(|on_set| (on_set, fork::slow_lock())),
             version: V::new_atomic().into(),
             set_lock: Mutex::new(()).into(),
-            value_by_cpu: (0..*NUM_CPUS)
-                .map(|cpu_id| AtomicPtr::new(per_cpu_rc::new(cpu_id as _, value.clone())).into())
+            indexing,
+            reclamation,
+            drop_policy: builder.drop_policy.clone(),
+            latest: Mutex::new(None),
+            // NOTE: The topology might describe more cpus than this system has.
+            shards: (0..*NUM_CPUS)
+                .map(|cpu| match topology.shard(cpu, granularity) {
+                    leader if leader < *NUM_CPUS => leader,
+                    _ => cpu,
+                })
+                .collect(),
+            nodes: numa::group_by_node(
+                topology,
+                *NUM_CPUS,
+                builder.numa_local && indexing == Indexing::Cpu,
+            ),
+            value_by_cpu: iter::repeat_with(|| AtomicPtr::default().into())
+                .take(*NUM_CPUS)
                 .collect(),
             new_value_by_cpu: iter::repeat_with(|| AtomicPtr::default().into())
                 .take(*NUM_CPUS)
                 .collect(),
+            installed_by_cpu: iter::repeat_with(|| {
+                Installed {
+                    generation: AtomicU64::new(stamp.generation),
+                    set_at: Default::default(),
+                }
+                .into()
+            })
+            .take(*NUM_CPUS)
+            .collect(),
+        };
+        match indexing {
+            Indexing::Cpu => {
+                let mut shared = vec![None; *NUM_CPUS];
+                for group in slf.nodes.iter() {
+                    let _policy = PreferNode::new(group.node);
+                    for &cpu in &group.cpus {
+                        let value = Versioned {
+                            version: V::new(),
+                            value: Stamped {
+                                value: slf.share(&mut shared, cpu, &value),
+                                stamp,
+                            },
+                        };
+                        slf.value_by_cpu[cpu].0.store(
+                            per_cpu_rc::new(slf.id, &slf.counters, cpu as _, group.node, value),
+                            Relaxed,
+                        );
+                    }
+                }
+            }
+            Indexing::ConcurrencyId => {
+                *slf.latest.get_mut() = Some(Versioned {
+                    version: V::new(),
+                    value: Stamped {
+                        value: Shared::new(value, &slf.drop_policy),
+                        stamp,
+                    },
+                });
+            }
         }
+        slf
+    }
+
+    /// Returns the copy of `value` for `cpu`. Cpus in the same shard receive the same copy.
+    fn share(
+        &self,
+        shared: &mut [Option<Arc<Shared<T>>>],
+        cpu: usize,
+        value: &T,
+    ) -> Arc<Shared<T>> {
+        shared[self.shards[cpu]]
+            .get_or_insert_with(|| Arc::new(Shared::new(value.clone(), &self.drop_policy)))
+            .clone()
+    }
+
+    fn is_populated(&self, cpu: usize) -> bool {
+        !self.value_by_cpu[cpu].0.load(Relaxed).is_null()
+    }
+
+    /// Returns the current time in nanoseconds since `created`.
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_nanos() as u64
+    }
+
+    /// Records that the value with `stamp` has been stored in `value_by_cpu[cpu]`.
+    #[inline]
+    unsafe fn installed(&self, cpu: usize, stamp: Stamp) {
+        let installed = &self.installed_by_cpu.get_unchecked(cpu).0;
+        installed.generation.store(stamp.generation, Relaxed);
+        installed.set_at.store(stamp.set_at, Relaxed);
     }
 
     #[inline]
     pub fn set(self: &Arc<Self>, value: T) {
-        let mut new: Box<_> = (0..*NUM_CPUS)
-            .map(|cpu_id| {
-                let value = Versioned {
-                    version: V::new(),
-                    value: value.clone(),
-                };
-                per_cpu_rc::new(cpu_id as _, value)
-            })
-            .collect();
-        if let Some(_lock) = self.set_lock.0.try_lock() {
+        // NOTE: Only instances with an `on_set` callback can fail.
+        let _ = self.try_set(value);
+    }
+
+    /// Like `set` but returns the error of the `on_set` callback. The value is not set if the
+    /// callback fails. Returns the version of the value or `None` if it has been dropped
+    /// because of a concurrent `set`.
+    ///
+    /// Instances with an `on_set` callback call it before taking the locks of `set`. Their
+    /// sets wait for each other and are never dropped.
+    pub fn try_set(self: &Arc<Self>, value: T) -> io::Result<Option<u64>> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("set", instance = self.id, name = self.name.as_deref()).entered();
+        let cpu = unsafe {
+            let rseq = get_rseq();
+            let cpu = *rseq::index(rseq, self.indexing);
+            deferred::drain(rseq, self.indexing, cpu);
+            cpu as usize
+        };
+        // NOTE: The callback might perform I/O. It must not block concurrent readers or `fork`.
+        let _on_set = match &self.on_set {
+            Some((on_set, lock)) => {
+                let lock = lock.lock();
+                let result = on_set(&value, self.generation.load(Relaxed) + 1);
+                #[cfg(feature = "tracing")]
+                if let Err(e) = &result {
+                    tracing::error!(error = %e, "value not set because the callback failed");
+                }
+                result?;
+                Some(lock)
+            }
+            None => None,
+        };
+        let mut shared = vec![None; *NUM_CPUS];
+        let mut new_value = |cpu_id: usize, node: Option<u32>| {
+            let value = Versioned {
+                version: V::new(),
+                value: Stamped {
+                    value: self.share(&mut shared, cpu_id, &value),
+                    stamp: Stamp::default(),
+                },
+            };
+            per_cpu_rc::new(self.id, &self.counters, cpu_id as _, node, value)
+        };
+        let mut new = Owned(iter::repeat_with(ptr::null_mut).take(*NUM_CPUS).collect());
+        for group in self.nodes.iter() {
+            let _policy = PreferNode::new(group.node);
+            for &cpu in &group.cpus {
+                if self.is_populated(cpu) {
+                    new.0[cpu] = new_value(cpu, group.node);
+                }
+            }
+        }
+        let _fork = fork::guard();
+        let lock = match self.on_set {
+            // The value has been passed to `on_set` and is installed. `set_lock` is only held
+            // by `populate`.
+            Some(_) => Some(self.set_lock.0.lock()),
+            None => self.set_lock.0.try_lock(),
+        };
+        self.counters.inc(
+            cpu,
+            match lock.is_some() {
+                true => Event::Set,
+                false => Event::SetDropped,
+            },
+        );
+        #[cfg(feature = "tracing")]
+        if lock.is_none() {
+            static SAMPLER: Sampler = Sampler::new();
+            if let Some(count) = SAMPLER.sample() {
+                tracing::debug!(count, "value dropped because of a concurrent set");
+            }
+        }
+        let mut installed = None;
+        if let Some(_lock) = lock {
+            // NOTE: Everything that might panic happens before the first copy is published.
+            for i in 0..*NUM_CPUS {
+                // Copies are only populated while holding `set_lock`.
+                if new.0[i].is_null() && self.is_populated(i) {
+                    new.0[i] = new_value(i, None);
+                }
+            }
+            let mut latest = self.latest.lock();
+            let latest_value = latest
+                .as_ref()
+                .map(|_| Shared::new(value.clone(), &self.drop_policy));
             let version = V::inc(V::get(&self.version.0));
+            let stamp = Stamp {
+                generation: self.generation.load(Relaxed) + 1,
+                set_at: self.now(),
+            };
             for i in 0..*NUM_CPUS {
+                if new.0[i].is_null() {
+                    continue;
+                }
                 unsafe {
-                    (*new[i]).value.version = version;
+                    (*new.0[i]).value.version = version;
+                    (*new.0[i]).value.value.stamp = stamp;
                 }
-                new[i] = self.new_value_by_cpu[i].0.swap(new[i], AcqRel);
+                new.0[i] = match self.reclamation {
+                    Reclamation::Readers => self.new_value_by_cpu[i].0.swap(new.0[i], AcqRel),
+                    Reclamation::Membarrier => unsafe {
+                        self.installed(i, stamp);
+                        self.value_by_cpu[i].0.swap(new.0[i], AcqRel)
+                    },
+                };
+            }
+            if let Some(value) = latest_value {
+                *latest = Some(Versioned {
+                    version,
+                    value: Stamped { value, stamp },
+                });
             }
+            drop(latest);
             V::set(&self.version.0, version);
+            self.generation.store(stamp.generation, Relaxed);
+            self.last_set.store(stamp.set_at, Relaxed);
+            installed = Some(stamp.generation);
+            if self.reclamation == Reclamation::Membarrier {
+                self.reclaim(&mut new.0);
+            }
         }
-        for &old in new.deref() {
-            if !old.is_null() {
-                unsafe {
-                    drop(Box::from_raw(old));
+        new.free();
+        Ok(installed)
+    }
+
+    /// Reclaims the values that have been replaced by `set` with `Reclamation::Membarrier`.
+    #[cold]
+    fn reclaim(&self, old: &mut [Value<V, T>]) {
+        /// How often we check if readers have released their references before we leave the
+        /// values to them.
+        const ATTEMPTS: usize = 16;
+        // Restart all `acquire` calls that might have loaded the old values. Afterwards, no
+        // new references to the old values can be acquired.
+        membarrier::restart_rseq();
+        // Readers might still be using the values that have not been freed. If we give up or
+        // if a `Drop` implementation panics, release our references and let the readers free
+        // them.
+        let old = ReleaseOnDrop {
+            indexing: self.indexing,
+            old,
+        };
+        for _ in 0..ATTEMPTS {
+            let mut pending = false;
+            for old in old.old.iter_mut().filter(|old| !old.is_null()) {
+                let value = mem::replace(old, ptr::null_mut());
+                if !unsafe { per_cpu_rc::try_free(value) } {
+                    *old = value;
+                    pending = true;
                 }
             }
+            if !pending {
+                return;
+            }
+            thread::yield_now();
         }
     }
 
+    /// Allocates the copy for `cpu` from the last value that was set.
+    #[cold]
+    #[inline(never)]
+    fn populate(&self, cpu: usize) {
+        let _fork = fork::guard();
+        let _lock = self.set_lock.0.lock();
+        if self.is_populated(cpu) {
+            return;
+        }
+        let latest = self.latest.lock();
+        let latest = latest.as_ref().unwrap();
+        let value = Versioned {
+            version: latest.version,
+            value: Stamped {
+                value: Arc::new(Shared::new(T::clone(&latest.value), &self.drop_policy)),
+                stamp: latest.value.stamp,
+            },
+        };
+        unsafe { self.installed(cpu, latest.value.stamp) };
+        self.value_by_cpu[cpu].0.store(
+            per_cpu_rc::new(self.id, &self.counters, cpu as _, None, value),
+            Release,
+        );
+    }
+
+    /// Installs the pending update of `cpu`, if any. Returns the replaced value whose
+    /// reference must be released by the caller.
     #[inline]
-    unsafe fn maybe_update(&self, rseq: *mut rseq::rseq) {
-        let cpu = (*rseq).cpu_id as usize;
+    unsafe fn maybe_update(&self, cpu: usize) -> Option<&Rc<V, T>> {
         let new = self.new_value_by_cpu.get_unchecked(cpu);
         if new.0.load(Relaxed).is_null() {
-            return;
+            return None;
         }
         let new = new.0.swap(ptr::null_mut(), Acquire);
         if new.is_null() {
-            return;
+            return None;
         }
+        self.installed(cpu, (*new).value.value.stamp);
         let old = self.value_by_cpu.get_unchecked(cpu).0.swap(new, AcqRel);
-        per_cpu_rc::release(rseq, &*old);
+        self.counters.inc(cpu, Event::Update);
+        Some(&*old)
+    }
+
+    /// Counts a stale read if a newer value has been set for the owner of `rc` since `rc` was
+    /// acquired.
+    #[inline]
+    fn count_stale_read(&self, rc: &Rc<V, T>) {
+        #[cfg(feature = "stats")]
+        {
+            let cpu = rc.cpu_id() as usize;
+            let stale = !self.new_value_by_cpu[cpu].0.load(Relaxed).is_null()
+                || !ptr::eq(self.value_by_cpu[cpu].0.load(Relaxed), rc);
+            if stale {
+                self.counters.inc(cpu, Event::StaleRead);
+            }
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = rc;
     }
 
     #[inline]
     pub fn get(self: &Arc<Self>) -> Versioned<V, T> {
         unsafe {
             let rseq = get_rseq();
-            self.maybe_update(rseq);
-            let rc = per_cpu_rc::acquire(rseq, &self.value_by_cpu);
-            let value = rc.value.clone();
-            per_cpu_rc::release(rseq, rc);
+            // NOTE: The pinned fast path does not use the `rseq` of other threads and is
+            // therefore not restarted by `Reclamation::Membarrier`.
+            if self.indexing == Indexing::Cpu
+                && self.reclamation == Reclamation::Readers
+                && pinned::is_pinned_to((*rseq).cpu_id)
+            {
+                if let Some(value) = self.get_pinned(rseq, (*rseq).cpu_id) {
+                    return value;
+                }
+            }
+            let index = rseq::index(rseq, self.indexing);
+            let cpu = *index;
+            deferred::drain(rseq, self.indexing, cpu);
+            if let Some(old) = self.maybe_update(cpu as usize) {
+                per_cpu_rc::release(rseq, self.indexing, old);
+            }
+            let rc = loop {
+                let rc = per_cpu_rc::acquire(rseq, index, &self.value_by_cpu);
+                if !rc.is_null() {
+                    break per_cpu_rc::Guard::new(rseq, self.indexing, &*rc);
+                }
+                // The index might have changed since we've read it above.
+                self.populate(*index as usize);
+            };
+            let value = Versioned {
+                version: rc.value.version,
+                value: T::clone(&rc.value.value),
+            };
+            self.count_stale_read(&rc);
             value
         }
     }
+
+    /// Like `get` but for a thread that is pinned to `cpu`. Returns `None` if the thread has
+    /// been migrated to another cpu.
+    #[inline]
+    unsafe fn get_pinned(&self, rseq: *mut rseq::rseq, cpu: u32) -> Option<Versioned<V, T>> {
+        deferred::drain(rseq, Indexing::Cpu, cpu);
+        if let Some(old) = self.maybe_update(cpu as usize) {
+            // NOTE: The thread might have been migrated since `is_pinned_to`. This is rare
+            // enough to use the checked release.
+            per_cpu_rc::release(rseq, Indexing::Cpu, old);
+        }
+        let rc = per_cpu_rc::acquire_pinned(rseq, cpu, &self.value_by_cpu);
+        if rc.is_null() {
+            return None;
+        }
+        let rc = per_cpu_rc::Guard::pinned(rseq, cpu, &*rc);
+        let value = Versioned {
+            version: rc.value.version,
+            value: T::clone(&rc.value.value),
+        };
+        self.count_stale_read(&rc);
+        Some(value)
+    }
+
+    pub fn inspect(self: &Arc<Self>) -> InstanceReport {
+        let probes = match self.indexing {
+            Indexing::Cpu => self.probe(),
+            // NOTE: Tasks cannot be run on a specific concurrency id.
+            Indexing::ConcurrencyId => Vec::new(),
+        };
+        let mut report = self.report(probes);
+        if let Some(heap_size) = &self.heap_size {
+            let _fork = fork::guard();
+            if let Some(latest) = &*self.latest.lock() {
+                report.heap_bytes += mem::size_of::<Shared<T>>() + heap_size(&latest.value);
+            }
+        }
+        report
+    }
+
+    /// Like `inspect` but does not run tasks on other cpus and does not take locks. Only the
+    /// reference count of the copy of the current cpu is read.
+    pub fn report_current_cpu(&self) -> InstanceReport {
+        let probe = match self.indexing {
+            Indexing::Cpu => unsafe { self.probe_current_cpu() },
+            Indexing::ConcurrencyId => None,
+        };
+        self.report(probe.into_iter().collect())
+    }
+
+    fn report(&self, probes: Vec<Probe>) -> InstanceReport {
+        let now = self.now();
+        let age = |set_at: u64| Duration::from_nanos(now.saturating_sub(set_at));
+        let mut per_cpu: Box<[CpuReport]> = (0..*NUM_CPUS)
+            .map(|cpu| {
+                let installed = &self.installed_by_cpu[cpu].0;
+                let populated = self.is_populated(cpu);
+                CpuReport {
+                    version: populated.then(|| installed.generation.load(Relaxed)),
+                    age: populated.then(|| age(installed.set_at.load(Relaxed))),
+                    pending: !self.new_value_by_cpu[cpu].0.load(Relaxed).is_null(),
+                    refcount: None,
+                }
+            })
+            .collect();
+        let mut heap_bytes = self.memory_usage();
+        let mut copies = HashSet::new();
+        for probe in probes {
+            per_cpu[probe.cpu].refcount = Some(probe.refcount);
+            if copies.insert(probe.copy) {
+                heap_bytes += probe.heap_size;
+            }
+        }
+        InstanceReport {
+            name: self.name.as_deref().map(Into::into),
+            version: self.generation.load(Relaxed),
+            since_last_set: age(self.last_set.load(Relaxed)),
+            per_cpu,
+            heap_bytes,
+        }
+    }
+
+    /// Reads the reference counts of the copies on the per-cpu threads. Returns the probes
+    /// that completed within 100ms.
+    fn probe(self: &Arc<Self>) -> Vec<Probe> {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let (tx, rx) = flume::unbounded();
+        for cpu in (0..*NUM_CPUS).filter(|&cpu| self.is_populated(cpu)) {
+            let slf = self.clone();
+            let tx = tx.clone();
+            let task = move || unsafe {
+                // The thread is not running on `cpu`. The reference count cannot be read.
+                if let Some(probe) = slf.probe_current_cpu().filter(|probe| probe.cpu == cpu) {
+                    let _ = tx.send(probe);
+                }
+            };
+            per_cpu_thread::run_on_cpu_uncounted(cpu, Box::new(task));
+        }
+        drop(tx);
+        let deadline = Instant::now() + TIMEOUT;
+        iter::from_fn(|| rx.recv_deadline(deadline).ok()).collect()
+    }
+
+    /// Reads the reference count of the copy of the cpu the thread is running on. Returns
+    /// `None` if the cpu does not have a copy or if the copy is owned by another cpu of its
+    /// shard.
+    ///
+    /// # Safety
+    ///
+    /// The instance must be indexed by cpu.
+    unsafe fn probe_current_cpu(&self) -> Option<Probe> {
+        let rseq = get_rseq();
+        let index = rseq::index(rseq, Indexing::Cpu);
+        let rc = per_cpu_rc::acquire(rseq, index, &self.value_by_cpu);
+        if rc.is_null() {
+            return None;
+        }
+        let rc = per_cpu_rc::Guard::new(rseq, Indexing::Cpu, &*rc);
+        let cpu = rc.cpu_id();
+        // NOTE: Exclude the reference held by this function.
+        let refcount = rc.refcount() - 1;
+        // The thread has been migrated since the copy was acquired.
+        if (*rseq).cpu_id != cpu {
+            return None;
+        }
+        Some(Probe {
+            cpu: cpu as usize,
+            refcount,
+            copy: Arc::as_ptr(&rc.value.value.value) as usize,
+            heap_size: match &self.heap_size {
+                Some(heap_size) => heap_size(&rc.value.value),
+                None => 0,
+            },
+        })
+    }
+}
+
+impl<V, T> Instance for Inner<V, T>
+where
+    V: Versioning,
+    T: Clone + Send + Sync + 'static,
+{
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    fn type_name(&self) -> &'static str {
+        any::type_name::<T>()
+    }
+
+    fn version(&self) -> u64 {
+        self.generation.load(Relaxed)
+    }
+
+    fn last_set(&self) -> SystemTime {
+        let since = self.now().saturating_sub(self.last_set.load(Relaxed));
+        SystemTime::now() - Duration::from_nanos(since)
+    }
+
+    fn counters(&self) -> Snapshot {
+        self.counters.snapshot()
+    }
+
+    fn memory_usage(&self) -> usize {
+        // NOTE: The copies cannot be dereferenced without acquiring a reference. Cpus in the
+        // same shard share a copy.
+        let populated = |slots: &[Slot<V, T>]| {
+            (0..slots.len())
+                .filter(|&cpu| !slots[cpu].0.load(Relaxed).is_null())
+                .collect::<Vec<_>>()
+        };
+        let current = populated(&self.value_by_cpu);
+        let pending = populated(&self.new_value_by_cpu);
+        let mut copies: Vec<_> = current.iter().map(|&cpu| self.shards[cpu]).collect();
+        copies.sort_unstable();
+        copies.dedup();
+        mem::size_of::<Self>()
+            + 2 * self.value_by_cpu.len() * mem::size_of::<Slot<V, T>>()
+            + (current.len() + pending.len()) * mem::size_of::<Rc<V, T>>()
+            // `Arc` stores two reference counts in front of the value.
+            + copies.len() * (2 * mem::size_of::<usize>() + mem::size_of::<Shared<T>>())
+    }
+
+    fn inspect(self: Arc<Self>) -> InstanceReport {
+        Inner::inspect(&self)
+    }
+
+    #[cfg(feature = "serde")]
+    fn read(self: Arc<Self>) -> Result<serde_json::Value, RegistryError> {
+        let codec = self.codec.ok_or(RegistryError::NotSerializable)?;
+        (codec.to_json)(&self.get().value).map_err(RegistryError::Serialize)
+    }
+
+    #[cfg(feature = "serde")]
+    fn write(self: Arc<Self>, value: serde_json::Value) -> Result<u64, RegistryError> {
+        let codec = self.codec.ok_or(RegistryError::NotSerializable)?;
+        self.try_set((codec.from_json)(value).map_err(RegistryError::Deserialize)?)
+            .map_err(RegistryError::Persist)?
+            .ok_or(RegistryError::Superseded)
+    }
+}
+
+/// The per-cpu values owned by `set`. They are freed when this is dropped, even if a `clone`
+/// panics.
+struct Owned<V: Versioning, T: Send + Sync>(Box<[Value<V, T>]>);
+
+impl<V: Versioning, T: Send + Sync> Owned<V, T> {
+    fn free(&mut self) {
+        for value in self.0.iter_mut() {
+            let value = mem::replace(value, ptr::null_mut());
+            if !value.is_null() {
+                unsafe {
+                    per_cpu_rc::free(value);
+                }
+            }
+        }
+    }
+}
+
+impl<V: Versioning, T: Send + Sync> Drop for Owned<V, T> {
+    fn drop(&mut self) {
+        // NOTE: If a `Drop` implementation panics in `free`, the remaining values are freed
+        // here.
+        self.free();
+    }
+}
+
+/// Releases the references to the old values that `reclaim` has not freed.
+struct ReleaseOnDrop<'a, V: Versioning, T: Send + Sync> {
+    indexing: Indexing,
+    old: &'a mut [Value<V, T>],
+}
+
+impl<V: Versioning, T: Send + Sync> Drop for ReleaseOnDrop<'_, V, T> {
+    fn drop(&mut self) {
+        // Take all values first. The owner of `old` must not free them if a `Drop`
+        // implementation panics below.
+        let old: Vec<_> = self
+            .old
+            .iter_mut()
+            .map(|old| mem::replace(old, ptr::null_mut()))
+            .filter(|old| !old.is_null())
+            .collect();
+        let rseq = get_rseq();
+        for old in old {
+            unsafe {
+                per_cpu_rc::release(rseq, self.indexing, &*old);
+            }
+        }
+    }
 }
 
 impl<V: Versioning, T: Send + Sync> Drop for Inner<V, T> {
@@ -125,17 +798,21 @@ impl<V: Versioning, T: Send + Sync> Drop for Inner<V, T> {
         // latest state of the `value_by_cpu` array and its contents.
         for value in self.value_by_cpu.iter() {
             let value = value.0.load(Acquire);
+            if value.is_null() {
+                // Never populated.
+                continue;
+            }
             unsafe {
                 // SAFETY: We're releasing the reference owned by the `value_by_cpu`
                 // array.
-                per_cpu_rc::release(rseq, &*value);
+                per_cpu_rc::release(rseq, self.indexing, &*value);
             }
         }
         for value in self.new_value_by_cpu.iter() {
             let value = value.0.load(Acquire);
             if !value.is_null() {
                 unsafe {
-                    drop(Box::from_raw(value));
+                    per_cpu_rc::free(value);
                 }
             }
         }
@@ -0,0 +1,38 @@
+//! See https://man7.org/linux/man-pages/man2/membarrier.2.html
+
+use once_cell::sync::Lazy;
+
+const MEMBARRIER_CMD_QUERY: libc::c_int = 0;
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ: libc::c_int = 1 << 7;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_RSEQ: libc::c_int = 1 << 8;
+
+fn membarrier(cmd: libc::c_int) -> libc::c_long {
+    unsafe { libc::syscall(libc::SYS_membarrier, cmd, 0, 0) }
+}
+
+/// Returns whether in-flight rseq critical sections can be restarted with membarrier.
+///
+/// Registers the process for `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ` on first use. This
+/// requires Linux 5.10 or later.
+pub fn rseq_available() -> bool {
+    static AVAILABLE: Lazy<bool> = Lazy::new(|| {
+        let supported = membarrier(MEMBARRIER_CMD_QUERY);
+        supported > 0
+            && supported & MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ as libc::c_long != 0
+            && membarrier(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_RSEQ) == 0
+    });
+    *AVAILABLE
+}
+
+/// Restarts all rseq critical sections of this process that are currently running on other
+/// cpus.
+///
+/// Must only be called if `rseq_available` returned true.
+pub fn restart_rseq() {
+    if membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ) != 0 {
+        panic!(
+            "Could not restart rseq critical sections: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
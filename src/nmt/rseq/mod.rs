@@ -1,11 +1,40 @@
 #![allow(non_upper_case_globals, non_camel_case_types, improper_ctypes)]
 
+use crate::nmt::Indexing;
 pub use inner::Inner;
 
 mod abort_on_drop;
 mod cache_line;
+pub mod counters;
+mod deferred;
+pub(crate) mod fork;
 mod inner;
+mod membarrier;
 mod num_cpus;
+pub mod numa;
 mod per_cpu_rc;
 pub mod per_cpu_thread;
+pub mod pinned;
+pub mod registry;
 mod rseq;
+#[cfg(feature = "tracing")]
+mod trace;
+
+/// Returns the number of cpus.
+pub fn num_cpus() -> usize {
+    *num_cpus::NUM_CPUS
+}
+
+/// See `stats::deferred_release_queue_depth`.
+pub fn deferred_release_queue_depth(cpu: usize) -> usize {
+    match cpu < *num_cpus::NUM_CPUS {
+        true => deferred::depth(Indexing::Cpu, cpu),
+        false => 0,
+    }
+}
+
+/// See `stats::live_per_cpu_objects`.
+#[cfg(feature = "debug-refcount")]
+pub fn live_per_cpu_objects() -> usize {
+    per_cpu_rc::live()
+}
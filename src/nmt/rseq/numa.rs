@@ -0,0 +1,253 @@
+use {
+    crate::{
+        nmt::inner::{
+            fork,
+            rseq::{get_rseq, node_id_available},
+        },
+        topology::Topology,
+    },
+    parking_lot::Mutex,
+    std::{alloc::Layout, collections::BTreeMap, mem, ptr},
+};
+
+const BITS_PER_USIZE: usize = mem::size_of::<usize>() * 8;
+
+/// The number of nodes supported by the largest kernel configuration (`CONFIG_NODES_SHIFT=10`)
+/// plus one word because the kernel ignores the last bit of a mask.
+const MASK_WORDS: usize = 1024 / BITS_PER_USIZE + 1;
+
+/// See linux/include/uapi/linux/mempolicy.h
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Returns the NUMA node of the cpu the current thread is running on.
+///
+/// This is read from the rseq area if the kernel supports it (Linux 6.3+) and from `getcpu`
+/// otherwise. Returns `None` if neither is available.
+pub fn current_numa_node() -> Option<u32> {
+    if node_id_available() {
+        unsafe {
+            return Some(ptr::read_volatile(ptr::addr_of!((*get_rseq()).node_id)));
+        }
+    }
+    let mut node = 0u32;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_getcpu,
+            ptr::null_mut::<u32>(),
+            &mut node as *mut u32,
+            ptr::null_mut::<u8>(),
+        )
+    };
+    match res {
+        0 => Some(node),
+        _ => None,
+    }
+}
+
+/// The cpus of a NUMA node.
+pub struct NodeCpus {
+    /// The node or `None` if the cpus should not be allocated on a specific node.
+    pub node: Option<u32>,
+    pub cpus: Vec<usize>,
+}
+
+/// Groups the cpus by their NUMA node.
+///
+/// If `numa_local` is false or there is only one node, returns a single group with node
+/// `None`.
+pub fn group_by_node(topology: &Topology, num_cpus: usize, numa_local: bool) -> Box<[NodeCpus]> {
+    let mut groups: Vec<NodeCpus> = vec![];
+    for cpu in 0..num_cpus {
+        let node = match numa_local {
+            true => topology.numa_node(cpu),
+            false => None,
+        };
+        match groups.iter_mut().find(|g| g.node == node) {
+            Some(group) => group.cpus.push(cpu),
+            None => groups.push(NodeCpus {
+                node,
+                cpus: vec![cpu],
+            }),
+        }
+    }
+    if groups.len() == 1 {
+        groups[0].node = None;
+    }
+    groups.into()
+}
+
+/// Prefers allocations on a NUMA node for the current thread while this object is alive.
+///
+/// This only affects pages that are faulted in by the current thread. Memory that the
+/// allocator has already obtained from the kernel is not moved. Use [`alloc_on_node`] for
+/// memory that must be on the node.
+pub struct PreferNode {
+    /// The policy of the thread before it was changed.
+    previous: Option<(libc::c_int, [usize; MASK_WORDS])>,
+}
+
+impl PreferNode {
+    /// Prefers allocations on `node`. Does nothing if `node` is `None` or if the memory policy
+    /// cannot be changed, for example because the kernel does not support NUMA.
+    pub fn new(node: Option<u32>) -> Self {
+        let previous = node.and_then(|node| {
+            let previous = get_mempolicy()?;
+            match set_mempolicy(MPOL_PREFERRED, &node_mask(node)) {
+                true => Some(previous),
+                false => None,
+            }
+        });
+        Self { previous }
+    }
+}
+
+impl Drop for PreferNode {
+    fn drop(&mut self) {
+        // NOTE: Restore the policy instead of resetting it. The application might have bound
+        // the thread to a node.
+        if let Some((mode, mask)) = &self.previous {
+            set_mempolicy(*mode, mask);
+        }
+    }
+}
+
+/// The size of the chunks that `alloc_on_node` maps and binds to a node.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The free slots of the chunks of each node, keyed by node and slot size.
+///
+/// Slots are never returned to the kernel. This keeps `mmap` and `munmap` off the paths that
+/// allocate and free per-cpu objects, and the pages of a freed slot stay on their node.
+static SLOTS: Mutex<BTreeMap<(u32, usize), Vec<usize>>> = parking_lot::const_mutex(BTreeMap::new());
+
+/// Locks the slots before a `fork`.
+pub fn lock() {
+    mem::forget(SLOTS.lock());
+}
+
+/// Unlocks the slots after a `fork`.
+///
+/// # Safety
+///
+/// `lock` must have been called by this thread.
+pub unsafe fn unlock(child: bool) {
+    match child {
+        true => fork::unlock_in_child(&SLOTS),
+        false => SLOTS.force_unlock(),
+    }
+}
+
+/// Returns the size of the slots used for `layout`.
+fn slot_size(layout: Layout) -> usize {
+    layout.pad_to_align().size().max(1)
+}
+
+/// Allocates memory for `layout` whose pages are allocated on `node`.
+///
+/// Small allocations from the global allocator usually share pages that have already been
+/// faulted in on another node. Instead, this allocates from chunks that are mapped and bound
+/// to the node once. Returns `None` if the layout is too large for a chunk or if a chunk
+/// cannot be mapped or bound to the node.
+pub fn alloc_on_node(layout: Layout, node: u32) -> Option<*mut u8> {
+    let size = slot_size(layout);
+    if size > CHUNK_SIZE / 8 || layout.align() > CHUNK_SIZE {
+        return None;
+    }
+    let mut slots = SLOTS.lock();
+    let free = slots.entry((node, size)).or_default();
+    if free.is_empty() {
+        let chunk = map_chunk(node)?;
+        // NOTE: Hand out the slots in ascending order.
+        free.extend((0..CHUNK_SIZE / size).rev().map(|i| chunk + i * size));
+    }
+    free.pop().map(|slot| slot as *mut u8)
+}
+
+/// Maps a chunk whose pages are allocated on `node`.
+fn map_chunk(node: u32) -> Option<usize> {
+    unsafe {
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            CHUNK_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return None;
+        }
+        let mask = node_mask(node);
+        let res = libc::syscall(
+            libc::SYS_mbind,
+            addr as usize,
+            CHUNK_SIZE,
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * BITS_PER_USIZE,
+            0,
+        );
+        if res != 0 {
+            libc::munmap(addr, CHUNK_SIZE);
+            return None;
+        }
+        Some(addr as usize)
+    }
+}
+
+/// Returns memory allocated with `alloc_on_node` to the chunks of its node.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `alloc_on_node` with the same layout and node.
+#[cfg(not(feature = "debug-refcount"))]
+pub unsafe fn free_on_node(ptr: *mut u8, layout: Layout, node: u32) {
+    SLOTS
+        .lock()
+        .entry((node, slot_size(layout)))
+        .or_default()
+        .push(ptr as usize);
+}
+
+/// Returns a mask that contains only `node`.
+fn node_mask(node: u32) -> [usize; MASK_WORDS] {
+    let node = node as usize;
+    let mut mask = [0; MASK_WORDS];
+    if let Some(word) = mask.get_mut(node / BITS_PER_USIZE) {
+        *word = 1 << (node % BITS_PER_USIZE);
+    }
+    mask
+}
+
+/// See https://man7.org/linux/man-pages/man2/get_mempolicy.2.html
+fn get_mempolicy() -> Option<(libc::c_int, [usize; MASK_WORDS])> {
+    let mut mode = 0;
+    let mut mask = [0; MASK_WORDS];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut mode as *mut libc::c_int,
+            mask.as_mut_ptr(),
+            mask.len() * BITS_PER_USIZE,
+            ptr::null_mut::<u8>(),
+            0,
+        )
+    };
+    match res {
+        0 => Some((mode, mask)),
+        _ => None,
+    }
+}
+
+/// See https://man7.org/linux/man-pages/man2/set_mempolicy.2.html
+fn set_mempolicy(mode: libc::c_int, mask: &[usize]) -> bool {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            mode,
+            mask.as_ptr(),
+            mask.len() * BITS_PER_USIZE,
+        )
+    };
+    res == 0
+}
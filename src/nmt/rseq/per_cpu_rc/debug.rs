@@ -0,0 +1,164 @@
+//! Checks of the `debug-refcount` feature.
+//!
+//! Freed objects are poisoned and never returned to the allocator. This way, double releases
+//! and releases of freed objects are detected reliably.
+
+use {
+    crate::nmt::inner::{fork, num_cpus::NUM_CPUS, per_cpu_rc::PerCpuRc},
+    once_cell::sync::Lazy,
+    parking_lot::Mutex,
+    std::{
+        any,
+        collections::BTreeMap,
+        io::{self, Write},
+        process, ptr,
+        sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    },
+};
+
+/// The value of the canaries of an object that is alive.
+pub const ALIVE: u32 = 0x11fe_c0de;
+/// The value of the canaries of an object that has been freed.
+pub const DEAD: u32 = 0xdead_c0de;
+
+static LIVE_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The objects that are alive per instance.
+struct Instance {
+    type_name: &'static str,
+    live: usize,
+}
+
+static INSTANCES: Lazy<Mutex<BTreeMap<u64, Instance>>> = Lazy::new(|| {
+    unsafe {
+        libc::atexit(report);
+    }
+    Default::default()
+});
+
+/// Locks the instances before a `fork`.
+pub fn lock() {
+    std::mem::forget(INSTANCES.lock());
+}
+
+/// Unlocks the instances after a `fork`.
+///
+/// # Safety
+///
+/// `lock` must have been called by this thread.
+pub unsafe fn unlock(child: bool) {
+    match child {
+        true => fork::unlock_in_child(&INSTANCES),
+        false => INSTANCES.force_unlock(),
+    }
+}
+
+/// Returns the number of per-cpu objects that are alive.
+pub fn live() -> usize {
+    LIVE_OBJECTS.load(Relaxed)
+}
+
+/// Prints the instances that still own per-cpu objects.
+extern "C" fn report() {
+    let instances = INSTANCES.lock();
+    let mut stderr = io::stderr();
+    for (id, instance) in instances.iter() {
+        let _ = writeln!(
+            stderr,
+            "lazy-atomic: instance {} ({}) leaked {} per-cpu objects",
+            id, instance.type_name, instance.live,
+        );
+    }
+}
+
+#[cold]
+fn fail(message: std::fmt::Arguments) -> ! {
+    let _ = writeln!(io::stderr(), "lazy-atomic: {}. Aborting.", message);
+    process::abort();
+}
+
+/// Records the allocation of a per-cpu object.
+pub fn allocated<T>(owner: u64) {
+    LIVE_OBJECTS.fetch_add(1, Relaxed);
+    INSTANCES
+        .lock()
+        .entry(owner)
+        .or_insert(Instance {
+            type_name: any::type_name::<T>(),
+            live: 0,
+        })
+        .live += 1;
+}
+
+/// Aborts if `data` is not alive or if its cpu is invalid.
+///
+/// # Safety
+///
+/// `data` must have been returned by `per_cpu_rc::new`.
+pub unsafe fn check<T>(data: *const PerCpuRc<T>, operation: &str) {
+    let canaries = (
+        ptr::read_volatile(ptr::addr_of!((*data).canary)),
+        ptr::read_volatile(ptr::addr_of!((*data).canary_end)),
+    );
+    match canaries {
+        (ALIVE, ALIVE) => {}
+        (DEAD, DEAD) => fail(format_args!("{} of a freed per-cpu object", operation)),
+        (start, end) => fail(format_args!(
+            "{} of a corrupted per-cpu object (canaries {:#x}, {:#x})",
+            operation, start, end,
+        )),
+    }
+    let cpu_id = (*data).cpu_id;
+    if cpu_id as usize >= *NUM_CPUS {
+        fail(format_args!(
+            "{} of a per-cpu object with invalid cpu {}",
+            operation, cpu_id
+        ));
+    }
+}
+
+/// Aborts if `data` is not alive or if it is not owned by `cpu`.
+///
+/// # Safety
+///
+/// `data` must have been returned by `per_cpu_rc::new`.
+pub unsafe fn check_pinned<T>(data: *const PerCpuRc<T>, cpu: u32, operation: &str) {
+    check(data, operation);
+    if (*data).cpu_id != cpu {
+        fail(format_args!(
+            "pinned {} for cpu {} of a per-cpu object owned by cpu {}",
+            operation,
+            cpu,
+            (*data).cpu_id,
+        ));
+    }
+}
+
+/// Drops the value and the counters of `data`, poisons the object and removes it from its
+/// instance.
+///
+/// # Safety
+///
+/// `data` must have been returned by `per_cpu_rc::new` and the caller must own the last
+/// reference.
+pub unsafe fn free<T>(data: *mut PerCpuRc<T>) {
+    check(data, "free");
+    (*data).canary = DEAD;
+    (*data).canary_end = DEAD;
+    LIVE_OBJECTS.fetch_sub(1, Relaxed);
+    {
+        let mut instances = INSTANCES.lock();
+        let owner = (*data).owner;
+        if let Some(instance) = instances.get_mut(&owner) {
+            instance.live -= 1;
+            if instance.live == 0 {
+                instances.remove(&owner);
+            }
+        }
+    }
+    // NOTE: The object itself is never freed, so everything it owns must be dropped here.
+    // Otherwise the counters of an instance would outlive it.
+    #[cfg(feature = "stats")]
+    ptr::drop_in_place(ptr::addr_of_mut!((*data).counters));
+    ptr::drop_in_place(ptr::addr_of_mut!((*data).value));
+}
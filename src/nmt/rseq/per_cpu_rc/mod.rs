@@ -7,18 +7,41 @@ cfg_if! {
     }
 }
 
-pub use arch::acquire;
+#[cfg(feature = "debug-refcount")]
+pub mod debug;
+
+#[cfg(feature = "stats")]
+use crate::nmt::inner::counters::Event;
+#[cfg(feature = "tracing")]
+use crate::nmt::inner::trace::Sampler;
+
+pub use arch::{acquire, acquire_pinned};
 use {
     crate::{
-        nmt::inner::{
-            cache_line::CacheLineAligned,
-            per_cpu_thread::run_on_cpu,
-            rseq::{get_rseq, rseq},
+        nmt::{
+            inner::{
+                cache_line::CacheLineAligned,
+                counters::PerCpuCounters,
+                deferred, numa,
+                rseq::{index, rseq},
+            },
+            Indexing,
         },
         stats::NUM_OFF_CPU_RELEASE,
     },
     cfg_if::cfg_if,
-    std::sync::atomic::Ordering::Relaxed,
+    std::{
+        alloc::Layout,
+        ops::Deref,
+        ptr,
+        sync::{
+            atomic::{
+                fence,
+                Ordering::{Acquire, Relaxed},
+            },
+            Arc,
+        },
+    },
 };
 
 /// A reference to a value that is owned by a single CPU.
@@ -27,21 +50,145 @@ pub struct PerCpuRc<T> {
     /// The reference count. If this drops to 0, the object will be freed.
     rc: u64,
     /// The id of the CPU that owns this structure. Not modified after initialization.
+    ///
+    /// If the value is indexed by concurrency id, this is the concurrency id that owns this
+    /// structure.
     cpu_id: u32,
+    /// `debug::ALIVE` while the object is alive.
+    #[cfg(feature = "debug-refcount")]
+    canary: u32,
+    /// The id of the instance that allocated this object.
+    #[cfg(feature = "debug-refcount")]
+    owner: u64,
+    /// The counters of the instance that allocated this object.
+    #[cfg(feature = "stats")]
+    counters: Arc<PerCpuCounters>,
+    /// The node this object has been allocated on with `numa::alloc_on_node`, if any.
+    node: Option<u32>,
     /// The stored value. Not modified after initialization.
     pub value: T,
+    /// `debug::ALIVE` while the object is alive.
+    #[cfg(feature = "debug-refcount")]
+    canary_end: u32,
     /// Ensure that this structure is cache-line aligned.
     _aligned: CacheLineAligned<()>,
 }
 
-/// Allocates a new per-cpu value for the given cpu.
-pub fn new<T: Send + Sync>(cpu_id: u32, value: T) -> *mut PerCpuRc<T> {
-    Box::leak(Box::new(PerCpuRc {
+impl<T> PerCpuRc<T> {
+    /// Returns the cpu or concurrency id that owns this structure.
+    pub fn cpu_id(&self) -> u32 {
+        self.cpu_id
+    }
+
+    /// Returns the reference count. Only accurate when called on the owning cpu.
+    pub fn refcount(&self) -> u64 {
+        // NOTE: See `try_free`.
+        unsafe { ptr::read_volatile(&self.rc) }
+    }
+}
+
+/// Allocates a new per-cpu value for the given cpu. `owner` identifies the instance that
+/// allocates the value.
+///
+/// If `node` is not `None`, the object is allocated on that NUMA node if possible.
+pub fn new<T: Send + Sync>(
+    owner: u64,
+    counters: &Arc<PerCpuCounters>,
+    cpu_id: u32,
+    node: Option<u32>,
+    value: T,
+) -> *mut PerCpuRc<T> {
+    #[cfg(feature = "debug-refcount")]
+    debug::allocated::<T>(owner);
+    #[cfg(not(feature = "debug-refcount"))]
+    let _ = owner;
+    #[cfg(not(feature = "stats"))]
+    let _ = counters;
+    let layout = Layout::new::<PerCpuRc<T>>();
+    let allocated = node.and_then(|node| Some((numa::alloc_on_node(layout, node)?, node)));
+    let data = PerCpuRc {
         rc: 1,
         cpu_id,
+        #[cfg(feature = "debug-refcount")]
+        canary: debug::ALIVE,
+        #[cfg(feature = "debug-refcount")]
+        owner,
+        #[cfg(feature = "stats")]
+        counters: counters.clone(),
+        node: allocated.map(|(_, node)| node),
         value,
+        #[cfg(feature = "debug-refcount")]
+        canary_end: debug::ALIVE,
         _aligned: Default::default(),
-    }))
+    };
+    match allocated {
+        Some((ptr, _)) => unsafe {
+            let ptr = ptr as *mut PerCpuRc<T>;
+            // NOTE: This faults in new pages of the chunk on the node.
+            ptr.write(data);
+            ptr
+        },
+        None => Box::leak(Box::new(data)),
+    }
+}
+
+/// Frees a per-cpu value.
+///
+/// # Safety
+///
+/// The caller must own the last reference to the value. The reference must be a pointer
+/// returned from `new` above.
+pub unsafe fn free<T: Send + Sync>(data: *mut PerCpuRc<T>) {
+    cfg_if! {
+        if #[cfg(feature = "debug-refcount")] {
+            debug::free(data);
+        } else {
+            match (*data).node {
+                Some(node) => {
+                    ptr::drop_in_place(data);
+                    numa::free_on_node(data as *mut u8, Layout::new::<PerCpuRc<T>>(), node);
+                }
+                None => drop(Box::from_raw(data)),
+            }
+        }
+    }
+}
+
+/// Frees a per-cpu value whose reference count has dropped to 0.
+///
+/// # Safety
+///
+/// Same as `free`.
+unsafe fn free_dead<T: Send + Sync>(data: *mut PerCpuRc<T>) {
+    #[cfg(feature = "stats")]
+    (*data).counters.inc((*data).cpu_id as usize, Event::Free);
+    free(data);
+}
+
+/// Returns the number of per-cpu values that are alive.
+#[cfg(feature = "debug-refcount")]
+pub fn live() -> usize {
+    debug::live()
+}
+
+/// Frees the per-cpu value if the caller holds the only reference to it.
+///
+/// # Safety
+///
+/// The caller must own a reference to the value and it must not be possible for other threads
+/// to acquire new references. The reference must be a pointer returned from `new` above.
+pub unsafe fn try_free<T: Send + Sync>(data: *mut PerCpuRc<T>) -> bool {
+    #[cfg(feature = "debug-refcount")]
+    debug::check(data, "reclamation");
+    // NOTE: The reference count is only modified on the owning cpu. Since no new references
+    // can be acquired, it can only decrease and it cannot reach 0 while we hold our reference.
+    if ptr::read_volatile(ptr::addr_of!((*data).rc)) != 1 {
+        return false;
+    }
+    // Synchronize with the accesses of readers that released their references.
+    fence(Acquire);
+    free(data);
+    true
 }
 
 // The following constants are the return values of `lazy_atomic_release_thread_pointer`.
@@ -68,51 +215,133 @@ const OFF_CPU: u64 = 2;
 ///
 /// The reference must be a pointer returned from `new` above.
 #[inline]
-pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, data: &PerCpuRc<T>) {
+pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, indexing: Indexing, data: &PerCpuRc<T>) {
+    #[cfg(feature = "debug-refcount")]
+    debug::check(data, "release");
     let cpu_id = data.cpu_id;
     let data = data as *const _ as _;
-    let res = arch::release(rseq, data);
+    let res = arch::release(rseq, index(rseq, indexing), data);
     if res != ALIVE {
         // We have to either deallocate the per-cpu data or retry the release.
-        release_slow::<T>(res, cpu_id, data);
+        release_slow::<T>(res, indexing, cpu_id, data);
+    }
+}
+
+/// A reference to a `PerCpuRc` that is released when the guard is dropped, even if the code
+/// using the reference panics.
+pub struct Guard<'a, T: Send + Sync> {
+    rseq: *mut rseq,
+    indexing: Indexing,
+    data: &'a PerCpuRc<T>,
+}
+
+impl<'a, T: Send + Sync> Guard<'a, T> {
+    /// Takes ownership of a reference that will be released with `release`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `release`. The guard must be dropped on the thread that created it.
+    #[inline]
+    pub unsafe fn new(rseq: *mut rseq, indexing: Indexing, data: &'a PerCpuRc<T>) -> Self {
+        #[cfg(feature = "debug-refcount")]
+        debug::check(data, "acquire");
+        Self {
+            rseq,
+            indexing,
+            data,
+        }
+    }
+
+    /// Takes ownership of a reference that has been acquired with `acquire_pinned`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `new`.
+    #[inline]
+    pub unsafe fn pinned(rseq: *mut rseq, cpu: u32, data: &'a PerCpuRc<T>) -> Self {
+        #[cfg(feature = "debug-refcount")]
+        debug::check_pinned(data, cpu, "acquire");
+        #[cfg(not(feature = "debug-refcount"))]
+        let _ = cpu;
+        // NOTE: The thread can be migrated while it holds the reference, e.g. if its affinity
+        // is changed by another thread or if a cpu is taken offline. The reference is
+        // therefore released with the checked `release`.
+        Self::new(rseq, Indexing::Cpu, data)
+    }
+}
+
+impl<T: Send + Sync> Deref for Guard<'_, T> {
+    type Target = PerCpuRc<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<T: Send + Sync> Drop for Guard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            release(self.rseq, self.indexing, self.data);
+        }
     }
 }
 
 #[cold]
-unsafe fn release_slow<T: Send + Sync>(res: u64, cpu_id: u32, data: *mut PerCpuRc<T>) {
+unsafe fn release_slow<T: Send + Sync>(
+    res: u64,
+    indexing: Indexing,
+    cpu_id: u32,
+    data: *mut PerCpuRc<T>,
+) {
     if res == DEAD {
         // The reference count has been reduced to 0. Deallocate the data.
-        drop(Box::from_raw(data));
+        free_dead(data);
         return;
     }
-    // res == OFF_CPU. This is the very-very slow path. Send the reference to the per-cpu thread
-    // so that it can be unreferenced there.
-    release_off_cpu(cpu_id, data);
+    // res == OFF_CPU. This is the very-very slow path. Let the next thread that runs on the
+    // owner unreference it.
+    release_off_cpu(indexing, cpu_id, data);
 }
 
 #[inline(never)]
-unsafe fn release_off_cpu<T: Send + Sync>(cpu_id: u32, data: *mut PerCpuRc<T>) {
+unsafe fn release_off_cpu<T: Send + Sync>(indexing: Indexing, cpu_id: u32, data: *mut PerCpuRc<T>) {
     NUM_OFF_CPU_RELEASE.fetch_add(1, Relaxed);
-    // We have to cast the pointer to usize because pointers are not `Send`.
-    let data = data as usize;
-    run_on_cpu(
-        cpu_id as usize,
-        Box::new(move || {
-            // Restore the pointer.
-            let data = data as *mut PerCpuRc<T>;
-            // NOTE: This code runs only on the cpu that owns the data. However, we cannot simply
-            // reduce the reference count using non-atomic operations. If we were to be rescheduled
-            // after checking the current reference count but before decrementing it, the behavior
-            // would be undefined. We could use atomic operations but that would be slower than
-            // using rseq.
-            let rseq = get_rseq();
-            let res = arch::release(rseq, data);
-            if res == DEAD {
-                drop(Box::from_raw(data));
-            } else {
-                // Sanity check.
-                assert_eq!(res, ALIVE);
-            }
-        }),
-    );
+    #[cfg(feature = "tracing")]
+    {
+        static SAMPLER: Sampler = Sampler::new();
+        if let Some(count) = SAMPLER.sample() {
+            tracing::debug!(
+                cpu = cpu_id,
+                ?indexing,
+                count,
+                "release deferred to another cpu"
+            );
+        }
+    }
+    deferred::push(indexing, cpu_id, data as _, release_deferred::<T>);
+}
+
+/// Implements `deferred::ReleaseFn` for `PerCpuRc<T>`.
+///
+/// NOTE: We cannot simply reduce the reference count using non-atomic operations even if we
+/// know that we're running on the owner. If we were to be rescheduled after checking the
+/// current reference count but before decrementing it, the behavior would be undefined. We
+/// could use atomic operations but that would be slower than using rseq.
+unsafe fn release_deferred<T: Send + Sync>(
+    rseq: *mut rseq,
+    indexing: Indexing,
+    data: *mut (),
+) -> bool {
+    let data = data as *mut PerCpuRc<T>;
+    #[cfg(feature = "debug-refcount")]
+    debug::check(data, "deferred release");
+    match arch::release(rseq, index(rseq, indexing), data) {
+        ALIVE => true,
+        DEAD => {
+            free_dead(data);
+            true
+        }
+        _ => false,
+    }
 }
@@ -8,19 +8,23 @@ use {
 /// use std::sync::atomic::Ordering::Acquire;
 /// unsafe fn acquire(
 ///     rseq: *mut rseq,
+///     index: *const u32,
 ///     data_by_cpu: &[CacheLineAligned<AtomicPtr<PerCpuRc<u8>>>],
-/// ) -> &PerCpuRc<u8> {
-///     let cpu = (*rseq).cpu_id;
-///     let data = &mut *data_by_cpu.get_unchecked(cpu as usize).0.load(Acquire);
-///     data.rc += 1;
+/// ) -> *const PerCpuRc<u8> {
+///     let cpu = *index;
+///     let data = data_by_cpu.get_unchecked(cpu as usize).0.load(Acquire);
+///     if !data.is_null() {
+///         (*data).rc += 1;
+///     }
 ///     data
 /// }
 /// ```
 #[inline]
 pub unsafe fn acquire<T: Send + Sync>(
     rseq: *mut rseq,
+    index: *const u32,
     data_by_cpu: &[CacheLineAligned<AtomicPtr<PerCpuRc<T>>>],
-) -> &PerCpuRc<T> {
+) -> *const PerCpuRc<T> {
     let data: *const PerCpuRc<T>;
     asm!(
         r#"
@@ -28,9 +32,11 @@ pub unsafe fn acquire<T: Send + Sync>(
     leaq 5f(%rip), {data}
     movq {data}, 8({rseq})
 2:
-    movl 4({rseq}), {data:e}
+    movl ({index}), {data:e}
     shlq $6, {data}
     movq ({data_by_cpu},{data}), {data}
+    testq {data}, {data}
+    jz 3f
     incq ({data})
 3:
     jmp 6f
@@ -51,19 +57,21 @@ pub unsafe fn acquire<T: Send + Sync>(
 6:
 "#,
         rseq = in(reg) rseq,
+        index = in(reg) index,
         data_by_cpu = in(reg) data_by_cpu.as_ptr(),
         data = out(reg) data,
         options(att_syntax),
     );
-    &*data
+    data
 }
 
 /// ```
 /// unsafe fn release(
 ///     rseq: *mut rseq,
+///     index: *const u32,
 ///     data: *mut PerCpuRc<u8>,
 /// ) -> u64 {
-///     let cpu = (*rseq).cpu_id;
+///     let cpu = *index;
 ///     let mut res = OFF_CPU;
 ///     if cpu == (*data).cpu_id {
 ///         res = ALIVE;
@@ -76,7 +84,11 @@ pub unsafe fn acquire<T: Send + Sync>(
 /// }
 /// ```
 #[inline]
-pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, data: *mut PerCpuRc<T>) -> u64 {
+pub unsafe fn release<T: Send + Sync>(
+    rseq: *mut rseq,
+    index: *const u32,
+    data: *mut PerCpuRc<T>,
+) -> u64 {
     let res: u64;
     asm!(
         r#"
@@ -84,7 +96,7 @@ pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, data: *mut PerCpuRc<T>) -
     leaq 5f(%rip), {tmp}
     movq {tmp}, 8({rseq})
 2:
-    movl 4({rseq}), {tmp:e}
+    movl ({index}), {tmp:e}
     movl $2, {res:e}
     cmpl 8({data}), {tmp:e}
     jne 6f
@@ -112,6 +124,7 @@ pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, data: *mut PerCpuRc<T>) -
 6:
 "#,
         rseq = in(reg) rseq,
+        index = in(reg) index,
         data = in(reg) data,
         tmp = out(reg) _,
         res = out(reg) res,
@@ -119,3 +132,68 @@ pub unsafe fn release<T: Send + Sync>(rseq: *mut rseq, data: *mut PerCpuRc<T>) -
     );
     res
 }
+
+/// ```no_run
+/// unsafe fn acquire_pinned(
+///     rseq: *mut rseq,
+///     cpu: u32,
+///     data_by_cpu: &[CacheLineAligned<AtomicPtr<PerCpuRc<u8>>>],
+/// ) -> *const PerCpuRc<u8> {
+///     let mut data = ptr::null();
+///     if (*rseq).cpu_id == cpu {
+///         data = data_by_cpu.get_unchecked(cpu as usize).0.load(Acquire);
+///         if !data.is_null() {
+///             (*data).rc += 1;
+///         }
+///     }
+///     data
+/// }
+/// ```
+///
+/// Like `acquire` but for a thread that is pinned to `cpu`. The slot is computed outside of
+/// the critical section. Returns null if the thread is no longer running on `cpu`.
+#[inline]
+pub unsafe fn acquire_pinned<T: Send + Sync>(
+    rseq: *mut rseq,
+    cpu: u32,
+    data_by_cpu: &[CacheLineAligned<AtomicPtr<PerCpuRc<T>>>],
+) -> *const PerCpuRc<T> {
+    let data: *const PerCpuRc<T>;
+    asm!(
+        r#"
+1:
+    leaq 5f(%rip), {data}
+    movq {data}, 8({rseq})
+2:
+    xorl {data:e}, {data:e}
+    cmpl 4({rseq}), {cpu:e}
+    jne 3f
+    movq ({slot}), {data}
+    testq {data}, {data}
+    jz 3f
+    incq ({data})
+3:
+    jmp 6f
+
+    # See above.
+    .ascii "\x0f\xb9\x3d\x53\x30\x05\x53"
+4:
+    jmp 1b
+
+5:
+    .long 0
+    .long 0
+    .quad 2b
+    .quad 3b - 2b
+    .quad 4b
+
+6:
+"#,
+        rseq = in(reg) rseq,
+        cpu = in(reg) cpu,
+        slot = in(reg) data_by_cpu.as_ptr().add(cpu as usize),
+        data = out(reg) data,
+        options(att_syntax),
+    );
+    data
+}
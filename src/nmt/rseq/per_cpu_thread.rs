@@ -1,19 +1,93 @@
 use {
-    crate::nmt::inner::{
-        abort_on_drop::AbortOnDrop, cache_line::CacheLineAligned, num_cpus::NUM_CPUS,
+    crate::{
+        config::{self, Config, PanicPolicy, Scheduling},
+        nmt::{
+            inner::{
+                abort_on_drop::AbortOnDrop,
+                cache_line::CacheLineAligned,
+                deferred, fork,
+                num_cpus::NUM_CPUS,
+                rseq::{get_rseq, rseq},
+            },
+            Indexing,
+        },
+        stats::NUM_HELPER_AFFINITY_LOST,
     },
-    flume::{Receiver, Sender},
-    once_cell::sync::Lazy,
+    flume::{Receiver, RecvTimeoutError, Sender},
+    once_cell::sync::{Lazy, OnceCell},
     parking_lot::Mutex,
-    std::{mem, thread},
+    std::{
+        io::{self, Write},
+        mem,
+        panic::{self, AssertUnwindSafe},
+        process, ptr,
+        sync::atomic::Ordering::Relaxed,
+        thread::{self, JoinHandle},
+    },
 };
 
+#[cfg(feature = "stats")]
+use crate::nmt::inner::counters::{self, Event};
+
 const BITS_PER_USIZE: usize = mem::size_of::<usize>() * 8;
 
+/// A task that performs releases that have been deferred to a cpu.
 pub type GcTask = Box<dyn FnOnce() + Send>;
 
+/// Runs tasks on specific cpus.
+///
+/// The crate uses this to perform releases that have been deferred to a cpu that is otherwise
+/// idle. By default, these tasks run on threads spawned by the crate, see [`HelperThreads`].
+/// Applications that already run a thread on every cpu can install their own executor with
+/// [`set_release_executor`].
+pub trait ReleaseExecutor: Send + Sync + 'static {
+    /// Runs `task` on `cpu`.
+    ///
+    /// The task should run on a thread whose affinity contains only `cpu`. The task can run on
+    /// any thread and at any time. If it runs on another cpu, it does nothing.
+    ///
+    /// This function must not block. If the task cannot be run, it should be dropped. Dropping
+    /// a task that drains a queue of deferred releases retries the drain later.
+    fn run_on_cpu(&self, cpu: usize, task: GcTask);
+
+    /// Prepares the executor such that `run_on_cpu` does not have to allocate resources.
+    ///
+    /// The default implementation does nothing.
+    fn prewarm(&self) {}
+
+    /// Runs all pending tasks and releases the resources of the executor.
+    ///
+    /// The executor must still accept tasks afterwards. The default implementation does
+    /// nothing.
+    fn shutdown(&self) {}
+}
+
+/// The default [`ReleaseExecutor`].
+///
+/// Spawns one thread per cpu the first time a task has to run on that cpu. The threads exit
+/// after the idle timeout of the [`Config`] or when [`shutdown`] is called. Panicking tasks are
+/// handled according to the [`PanicPolicy`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HelperThreads;
+
+static EXECUTOR: OnceCell<Box<dyn ReleaseExecutor>> = OnceCell::new();
+
+/// Installs the executor for tasks that have to run on a specific cpu.
+///
+/// This must be called before the first such task is run. Fails and returns the executor if
+/// an executor has already been installed or if the default executor is already in use.
+pub fn set_release_executor(
+    executor: Box<dyn ReleaseExecutor>,
+) -> Result<(), Box<dyn ReleaseExecutor>> {
+    EXECUTOR.set(executor)
+}
+
+fn executor() -> &'static dyn ReleaseExecutor {
+    &**EXECUTOR.get_or_init(|| Box::new(HelperThreads))
+}
+
 /// See https://man7.org/linux/man-pages/man2/sched_setaffinity.2.html
-fn sched_setaffinity(pid: libc::pid_t, mask: &[usize]) {
+pub fn sched_setaffinity(pid: libc::pid_t, mask: &[usize]) -> io::Result<()> {
     unsafe {
         let res = libc::syscall(
             libc::SYS_sched_setaffinity,
@@ -22,67 +96,343 @@ fn sched_setaffinity(pid: libc::pid_t, mask: &[usize]) {
             mask.as_ptr() as usize,
         );
         if res == -1 {
-            panic!(
-                "Could not set affinity: {}",
-                std::io::Error::last_os_error()
-            );
+            return Err(io::Error::last_os_error());
         }
     }
+    Ok(())
 }
 
-fn cpu_thread(cpu: usize, rx: Receiver<GcTask>) {
-    // set_priority(1);
-
-    // Not strictly necessary but we'll OOM if the thread dies anyway.
-    let _abort = AbortOnDrop;
-
-    // Ensure that this function runs only on cpu `cpu`.
+/// Returns an affinity mask that contains only `cpu`.
+pub fn cpu_mask(cpu: usize) -> Vec<usize> {
     let idx = cpu / BITS_PER_USIZE;
     let offset = cpu % BITS_PER_USIZE;
     let mut items = vec![0; idx + 1];
     items[idx] = 1 << offset;
-    sched_setaffinity(0, &items);
+    items
+}
+
+/// Applies the scheduling policy to the current thread.
+fn set_scheduling(scheduling: Scheduling) -> io::Result<()> {
+    let (policy, priority) = match scheduling {
+        Scheduling::Inherit => return Ok(()),
+        Scheduling::Other { nice } => {
+            // NOTE: The inherited policy might be a real-time policy.
+            let param = libc::sched_param { sched_priority: 0 };
+            if unsafe { libc::sched_setscheduler(0, libc::SCHED_OTHER, &param) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // NOTE: On Linux, the nice value is a per-thread attribute.
+            let res = unsafe {
+                let tid = libc::syscall(libc::SYS_gettid);
+                libc::setpriority(libc::PRIO_PROCESS, tid as _, nice)
+            };
+            return match res {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            };
+        }
+        Scheduling::Fifo { priority } => (libc::SCHED_FIFO, priority),
+        Scheduling::RoundRobin { priority } => (libc::SCHED_RR, priority),
+    };
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    match unsafe { libc::sched_setscheduler(0, policy, &param) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether the current thread runs on `cpu`. If it does not, tries to move the thread
+/// back to `cpu`.
+fn ensure_on_cpu(rseq: *mut rseq, cpu: usize) -> bool {
+    let current = || unsafe { ptr::read_volatile(ptr::addr_of!((*rseq).cpu_id)) as usize };
+    if current() == cpu {
+        return true;
+    }
+    // The cpu has been unplugged or removed from our cpuset, or someone has changed our
+    // affinity. In the first two cases the kernel has reset our affinity to the allowed cpus.
+    NUM_HELPER_AFFINITY_LOST.fetch_add(1, Relaxed);
+    let _ = sched_setaffinity(0, &cpu_mask(cpu));
+    current() == cpu
+}
+
+fn cpu_thread(cpu: usize, rx: Receiver<GcTask>) {
+    // Not strictly necessary but we'll OOM if the thread dies anyway.
+    let _abort = AbortOnDrop("a per-cpu thread panicked");
+
+    // Try to ensure that this function runs only on cpu `cpu`. This fails if the cpu is not
+    // in our cpuset. Tasks are checked below.
+    let _ = sched_setaffinity(0, &cpu_mask(cpu));
+
+    let config = config::get();
+    // NOTE: We're not allowed to use a real-time policy without the necessary privileges.
+    // Keep running with the inherited policy in this case.
+    let res = set_scheduling(config.scheduling);
+    #[cfg(feature = "tracing")]
+    if let Err(e) = &res {
+        tracing::warn!(
+            cpu,
+            scheduling = ?config.scheduling,
+            error = %e,
+            "could not set the scheduling policy of the per-cpu thread"
+        );
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = res;
+    if let Some(hook) = &config.on_thread_start {
+        hook(cpu);
+    }
 
-    // NOTE: If this cpu is unplugged at runtime, the kernel automatically changes the
-    // affinity mask back to the default. In this case the code below will likely cause
-    // memory corruption. No easy way to prevent this. I consider this a /proc/self/mem
-    // situation.
+    // Register the thread with rseq before running the first task.
+    let rseq = get_rseq();
 
     // Run all tasks
     loop {
-        rx.recv().unwrap()();
+        let res = match config.idle_timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            // NOTE: If we're not running on `cpu`, drop the task. Deferred releases stay in
+            // their queue until a thread runs on `cpu` again. The tasks themselves use rseq to
+            // check the cpu, so a migration while a task is running is harmless.
+            Ok(task) => {
+                if ensure_on_cpu(rseq, cpu) {
+                    run_task(config, cpu, task);
+                }
+            }
+            // The thread has been shut down and all tasks have run.
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                if exit_idle(cpu, &rx) {
+                    break;
+                }
+            }
+        }
+    }
+
+    mem::forget(_abort);
+}
+
+/// Runs a task and handles its panic according to the configuration.
+fn run_task(config: &Config, cpu: usize, task: GcTask) {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(task)) {
+        Ok(()) => return,
+        Err(payload) => payload,
+    };
+    if let Some(hook) = &config.on_task_panic {
+        hook(cpu, &*payload);
+    }
+    if config.panic_policy == PanicPolicy::Abort {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => payload
+                .downcast_ref::<String>()
+                .map(|m| &**m)
+                .unwrap_or("<unknown>"),
+        };
+        let _ = writeln!(
+            io::stderr(),
+            "lazy-atomic: a task panicked on the per-cpu thread of cpu {}: {}. Aborting.",
+            cpu,
+            message,
+        );
+        process::abort();
     }
 }
 
-fn create_cpu_thread(cpu: usize) -> Sender<GcTask> {
-    let (tx, rx) = flume::unbounded();
-    thread::Builder::new()
+/// Removes the current thread from `THREADS` unless a task has been sent to it in the
+/// meantime. Returns whether the thread should exit.
+fn exit_idle(cpu: usize, rx: &Receiver<GcTask>) -> bool {
+    let mut thread = THREADS[cpu].lock();
+    // NOTE: Tasks are only sent while holding the lock.
+    if !rx.is_empty() {
+        return false;
+    }
+    match &*thread {
+        Some(t) if t.handle.thread().id() == thread::current().id() => {
+            *thread = None;
+            true
+        }
+        // `shutdown` has already removed the thread. Wait for it to drop the sender.
+        _ => false,
+    }
+}
+
+fn create_cpu_thread(cpu: usize) -> io::Result<CpuThread> {
+    let config = config::get();
+    let (tx, rx) = match config.channel_bound {
+        Some(bound) => flume::bounded(bound),
+        None => flume::unbounded(),
+    };
+    let mut builder = thread::Builder::new()
         // NOTE: Maximum length is 15 bytes. The maximum is therefore `la-per-cpu 9999`.
-        .name(format!("la-per-cpu {}", cpu))
-        .spawn(move || cpu_thread(cpu, rx))
-        .expect("Could not spawn thread");
-    tx
+        .name(format!("{} {}", config.name_prefix, cpu));
+    if let Some(stack_size) = config.stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+    let handle = match builder.spawn(move || cpu_thread(cpu, rx)) {
+        Ok(handle) => handle,
+        Err(e) => {
+            #[cfg(feature = "tracing")]
+            tracing::error!(cpu, error = %e, "could not spawn per-cpu thread");
+            return Err(e);
+        }
+    };
+    #[cfg(feature = "tracing")]
+    tracing::debug!(cpu, "spawned per-cpu thread");
+    Ok(CpuThread {
+        sender: tx,
+        handle,
+        _aligned: Default::default(),
+    })
 }
 
 struct CpuThread {
     sender: Sender<GcTask>,
+    handle: JoinHandle<()>,
     _aligned: CacheLineAligned<()>,
 }
 
 static THREADS: Lazy<Box<[Mutex<Option<CpuThread>>]>> = Lazy::new(|| {
+    fork::register();
     std::iter::repeat_with(Default::default)
         .take(*NUM_CPUS)
         .collect()
 });
 
-/// Runs the task on the specified CPU.
-pub fn run_on_cpu(cpu: usize, task: GcTask) {
-    let mut thread = THREADS[cpu].lock();
+/// Locks the threads of all cpus. Called before `fork`.
+pub(super) fn lock_threads() {
+    if let Some(threads) = Lazy::get(&THREADS) {
+        for thread in threads.iter() {
+            mem::forget(thread.lock());
+        }
+    }
+}
+
+/// Unlocks the threads locked by `lock_threads`. In the child of a `fork`, the threads no
+/// longer exist and are spawned again when the next task has to run on their cpu.
+///
+/// # Safety
+///
+/// `lock_threads` must have been called by this thread.
+pub(super) unsafe fn unlock_threads(child: bool) {
+    if let Some(threads) = Lazy::get(&THREADS) {
+        for thread in threads.iter() {
+            if child {
+                // NOTE: Dropping the thread would detach a thread that does not exist and
+                // might access the channel while its lock is held by a thread that does not
+                // exist.
+                mem::forget((*thread.data_ptr()).take());
+                fork::unlock_in_child(thread);
+            } else {
+                thread.force_unlock();
+            }
+        }
+    }
+}
+
+/// Returns the thread of `cpu`, spawning it if necessary.
+fn get_or_spawn(thread: &mut Option<CpuThread>, cpu: usize) -> io::Result<&CpuThread> {
     if thread.is_none() {
-        *thread = Some(CpuThread {
-            sender: create_cpu_thread(cpu),
-            _aligned: Default::default(),
-        });
+        *thread = Some(create_cpu_thread(cpu)?);
     }
-    let _ = thread.as_ref().unwrap().sender.send(task);
+    Ok(thread.as_ref().unwrap())
+}
+
+/// Returns the number of tasks queued for the thread of `cpu` or `None` if the thread is not
+/// running. Does not spawn the thread.
+pub fn helper_thread_queue(cpu: usize) -> Option<usize> {
+    let threads = Lazy::get(&THREADS)?;
+    let thread = threads.get(cpu)?.lock();
+    thread.as_ref().map(|thread| thread.sender.len())
+}
+
+impl ReleaseExecutor for HelperThreads {
+    /// If the thread cannot be spawned or if its queue is full, the task is dropped and its
+    /// drain is retried by the timer.
+    fn run_on_cpu(&self, cpu: usize, task: GcTask) {
+        let mut thread = THREADS[cpu].lock();
+        if let Ok(thread) = get_or_spawn(&mut thread, cpu) {
+            let _ = thread.sender.try_send(task);
+        }
+    }
+
+    /// Spawns the threads of all cpus.
+    fn prewarm(&self) {
+        for (cpu, thread) in THREADS.iter().enumerate() {
+            let _ = get_or_spawn(&mut thread.lock(), cpu);
+        }
+    }
+
+    /// Performs the releases that are deferred to cpus and concurrency ids and joins the
+    /// threads.
+    fn shutdown(&self) {
+        let by_cid = (0..*NUM_CPUS).any(|cid| deferred::depth(Indexing::ConcurrencyId, cid) > 0);
+        if by_cid {
+            // NOTE: This changes the affinity of the thread.
+            if let Ok(drain) = thread::Builder::new()
+                .name(format!("{} cid", config::get().name_prefix))
+                .spawn(deferred::drain_cids)
+            {
+                let _ = drain.join();
+            }
+        }
+        for (cpu, thread) in THREADS.iter().enumerate() {
+            let thread = match thread.lock().take() {
+                Some(thread) => thread,
+                // Releases below the drain threshold do not spawn the thread.
+                None if deferred::depth(Indexing::Cpu, cpu) > 0 => match create_cpu_thread(cpu) {
+                    Ok(thread) => thread,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let _ = thread.sender.send(Box::new(move || unsafe {
+                deferred::drain(get_rseq(), Indexing::Cpu, cpu as u32);
+            }));
+            drop(thread.sender);
+            // NOTE: A task that calls `shutdown` must not join its own thread.
+            if thread.handle.thread().id() != thread::current().id() {
+                let _ = thread.handle.join();
+            }
+        }
+    }
+}
+
+/// Runs the task on the specified CPU using the installed [`ReleaseExecutor`].
+pub fn run_on_cpu(cpu: usize, task: GcTask) {
+    #[cfg(feature = "stats")]
+    let task: GcTask = {
+        counters::inc_global(cpu, Event::TaskQueued);
+        Box::new(move || {
+            task();
+            counters::inc_global(cpu, Event::TaskRun);
+        })
+    };
+    executor().run_on_cpu(cpu, task);
+}
+
+/// Runs the task like `run_on_cpu` without counting it. Used for tasks that do not release
+/// anything, e.g. the probes of `inspect`.
+pub(super) fn run_on_cpu_uncounted(cpu: usize, task: GcTask) {
+    executor().run_on_cpu(cpu, task);
+}
+
+/// Prepares the installed [`ReleaseExecutor`] so that deferred releases do not have to wait
+/// for it to allocate resources.
+///
+/// With the default executor, this spawns the per-cpu threads.
+pub fn prewarm() {
+    executor().prewarm();
+}
+
+/// Runs all tasks that are pending in the installed [`ReleaseExecutor`] and releases its
+/// resources.
+///
+/// With the default executor, this joins the per-cpu threads. The threads are spawned again
+/// if a release has to be deferred afterwards.
+pub fn shutdown() {
+    executor().shutdown();
 }
@@ -0,0 +1,59 @@
+use {
+    crate::nmt::inner::{
+        num_cpus::NUM_CPUS,
+        per_cpu_thread::{cpu_mask, sched_setaffinity},
+    },
+    std::{cell::Cell, io},
+};
+
+/// The value of `PINNED` if the thread is not pinned.
+pub const NOT_PINNED: u32 = u32::MAX;
+
+thread_local! {
+    /// Contains the cpu the thread is pinned to or `NOT_PINNED`.
+    static PINNED: Cell<u32> = const { Cell::new(NOT_PINNED) };
+}
+
+/// Pins the current thread to `cpu`.
+///
+/// This sets the affinity of the thread to `cpu`. Afterwards, accesses to atomics from this
+/// thread read the copy of `cpu` directly instead of looking up the copy of the cpu they run on.
+///
+/// The affinity of the thread should not be changed by other means while the thread is
+/// pinned. Such changes are detected the next time the thread accesses an atomic and the
+/// thread is unpinned. Accesses that are in progress while the thread is migrated release
+/// their references like unpinned accesses and are therefore safe.
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    if cpu >= *NUM_CPUS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cpu {} does not exist", cpu),
+        ));
+    }
+    sched_setaffinity(0, &cpu_mask(cpu))?;
+    PINNED.with(|p| p.set(cpu as u32));
+    Ok(())
+}
+
+/// Marks the current thread as no longer pinned.
+///
+/// This does not change the affinity of the thread.
+pub fn unpin_current_thread() {
+    PINNED.with(|p| p.set(NOT_PINNED));
+}
+
+/// Returns whether the thread is pinned to `cpu`, the cpu it is currently running on.
+///
+/// If the thread is pinned to another cpu, its affinity has been changed behind our back and
+/// the thread is unpinned.
+#[inline(always)]
+pub fn is_pinned_to(cpu: u32) -> bool {
+    let pinned = PINNED.with(|p| p.get());
+    if pinned == cpu {
+        return true;
+    }
+    if pinned != NOT_PINNED {
+        unpin_current_thread();
+    }
+    false
+}
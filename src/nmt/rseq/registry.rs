@@ -0,0 +1,69 @@
+//! The instances that have been given a name. Used by `registry` and
+//! `stats::render_openmetrics`.
+
+#[cfg(feature = "serde")]
+use crate::registry::RegistryError;
+use {
+    crate::{
+        nmt::{inner::fork, inspect::InstanceReport},
+        stats::Snapshot,
+    },
+    parking_lot::Mutex,
+    std::{
+        sync::{Arc, Weak},
+        time::SystemTime,
+    },
+};
+
+/// A type-erased instance.
+pub trait Instance: Send + Sync {
+    fn name(&self) -> &str;
+    fn type_name(&self) -> &'static str;
+    /// The number of values installed by `set`.
+    fn version(&self) -> u64;
+    /// The time of the last `set` or the creation time if the value has never been set.
+    fn last_set(&self) -> SystemTime;
+    fn counters(&self) -> Snapshot;
+    /// An estimate of the memory used by the per-cpu copies, excluding memory owned by the
+    /// values.
+    fn memory_usage(&self) -> usize;
+    fn inspect(self: Arc<Self>) -> InstanceReport;
+    #[cfg(feature = "serde")]
+    fn read(self: Arc<Self>) -> Result<serde_json::Value, RegistryError>;
+    #[cfg(feature = "serde")]
+    /// Returns the version of the value.
+    fn write(self: Arc<Self>, value: serde_json::Value) -> Result<u64, RegistryError>;
+}
+
+static INSTANCES: Mutex<Vec<Weak<dyn Instance>>> = parking_lot::const_mutex(Vec::new());
+
+/// Registers a named instance. The instance is removed once it has been dropped.
+pub fn register(instance: Weak<dyn Instance>) {
+    let mut instances = INSTANCES.lock();
+    instances.retain(|i| i.strong_count() > 0);
+    instances.push(instance);
+}
+
+/// Returns the named instances that are alive.
+pub fn instances() -> Vec<Arc<dyn Instance>> {
+    let mut instances = INSTANCES.lock();
+    instances.retain(|i| i.strong_count() > 0);
+    instances.iter().filter_map(|i| i.upgrade()).collect()
+}
+
+/// Locks the registry before a `fork`.
+pub fn lock() {
+    std::mem::forget(INSTANCES.lock());
+}
+
+/// Unlocks the registry after a `fork`.
+///
+/// # Safety
+///
+/// `lock` must have been called by this thread.
+pub unsafe fn unlock(child: bool) {
+    match child {
+        true => fork::unlock_in_child(&INSTANCES),
+        false => INSTANCES.force_unlock(),
+    }
+}
@@ -59,7 +59,11 @@
 //!  *   F1. <failure>
 //!  */
 
-use std::{arch::asm, cell::Cell, ptr};
+use {
+    crate::nmt::Indexing,
+    once_cell::sync::Lazy,
+    std::{arch::asm, cell::Cell, ptr},
+};
 
 /// This struct is here merely for illustration. Actual instances of the struct are defined
 /// in assembly.
@@ -86,8 +90,20 @@ pub struct rseq {
     /// Pointer to the currently active `resq_cs` cast to u64.
     pub rseq_cs: u64,
     pub flags: u32,
+    /// The NUMA node of `cpu_id`. Only valid if the kernel supports it (Linux 6.3+).
+    pub node_id: u32,
+    /// The concurrency id of the thread. Only valid if the kernel supports it (Linux 6.3+).
+    ///
+    /// Concurrency ids are unique among the threads of the process that are currently running
+    /// and are always smaller than the number of threads and the number of allowed cpus.
+    pub mm_cid: u32,
 }
 
+/// The offset of the end of the `node_id` field in the rseq structure.
+const NODE_ID_END: usize = 24;
+/// The offset of the end of the `mm_cid` field in the rseq structure.
+const MM_CID_END: usize = 28;
+
 thread_local! {
     /// Contains a pointer to the thread's rseq structure or null if it's never been accessed.
     static RSEQ: Cell<*mut rseq> = const { Cell::new(ptr::null_mut()) };
@@ -111,6 +127,13 @@ fn get_rseq_slow() -> *mut rseq {
             asm!("movq %fs:0, {tp}", tp = out(reg) tp, options(att_syntax));
         }
         rseq = tp.add(__rseq_offset) as *mut rseq;
+        /// See glibc/sysdeps/unix/sysv/linux/sys/rseq.h
+        #[cfg(feature = "tracing")]
+        const RSEQ_CPU_ID_REGISTRATION_FAILED: u32 = -2i32 as u32;
+        #[cfg(feature = "tracing")]
+        if (*rseq).cpu_id == RSEQ_CPU_ID_REGISTRATION_FAILED {
+            tracing::error!("rseq registration failed for this thread");
+        }
     }
     RSEQ.with(|thread_local| thread_local.set(rseq));
     rseq
@@ -137,6 +160,10 @@ pub fn ensure_enabled() {
         static __rseq_size: usize;
     }
     unsafe {
+        #[cfg(feature = "tracing")]
+        if __rseq_size == 0 {
+            tracing::error!("rseq is not available or has been disabled");
+        }
         assert!(
             __rseq_size > 0,
             "rseq is not available or has been disabled"
@@ -144,6 +171,43 @@ pub fn ensure_enabled() {
     }
 }
 
+/// Returns the size of the prefix of the rseq structure that is populated by the kernel.
+fn feature_size() -> usize {
+    static SIZE: Lazy<usize> = Lazy::new(|| {
+        extern "C" {
+            static __rseq_size: usize;
+        }
+        /// See linux/include/uapi/linux/auxvec.h
+        const AT_RSEQ_FEATURE_SIZE: libc::c_ulong = 27;
+        // NOTE: `__rseq_size` is the size of the area registered by glibc. The kernel only
+        // populates the fields it knows about.
+        unsafe { __rseq_size.min(libc::getauxval(AT_RSEQ_FEATURE_SIZE) as usize) }
+    });
+    *SIZE
+}
+
+/// Returns whether the `node_id` field of the rseq structure is populated by the kernel.
+pub fn node_id_available() -> bool {
+    feature_size() >= NODE_ID_END
+}
+
+/// Returns whether the `mm_cid` field of the rseq structure is populated by the kernel.
+pub fn mm_cid_available() -> bool {
+    feature_size() >= MM_CID_END
+}
+
+/// Returns a pointer to the field of the rseq structure that contains the current index.
+///
+/// The value of this field can only change when the thread is preempted. It can therefore be
+/// used to identify the owner of per-index data inside an rseq critical section.
+#[inline(always)]
+pub unsafe fn index(rseq: *mut rseq, indexing: Indexing) -> *const u32 {
+    match indexing {
+        Indexing::Cpu => ptr::addr_of!((*rseq).cpu_id),
+        Indexing::ConcurrencyId => ptr::addr_of!((*rseq).mm_cid),
+    }
+}
+
 // NOTE: Despite not having a branch, the following code is slower than the above.
 // #[inline(always)]
 // pub fn get_rseq() -> *mut rseq {
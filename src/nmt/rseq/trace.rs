@@ -0,0 +1,26 @@
+//! Support for the `tracing` feature.
+
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Frequent slow-path events are only emitted for the first and every `SAMPLE_INTERVAL`th
+/// occurrence.
+const SAMPLE_INTERVAL: u64 = 1024;
+
+/// Counts the occurrences of a frequent event.
+pub struct Sampler(AtomicU64);
+
+impl Sampler {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Records an occurrence. Returns the number of occurrences so far if this occurrence
+    /// should be emitted.
+    pub fn sample(&self) -> Option<u64> {
+        let n = self.0.fetch_add(1, Relaxed);
+        match n.is_multiple_of(SAMPLE_INTERVAL) {
+            true => Some(n + 1),
+            false => None,
+        }
+    }
+}
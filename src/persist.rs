@@ -0,0 +1,285 @@
+//! Snapshots of atomics on disk.
+//!
+//! [`Persisted`] writes the value of an [`AtomicNmt`] to a file whenever it is set and restores
+//! it when the process starts again. This includes values set through the
+//! [`registry`](crate::registry), for example with the [`admin`](crate::admin) endpoint.
+//!
+//! A snapshot is a header line followed by the value as JSON:
+//!
+//! ```text
+//! lazy-atomic-snapshot 1 <version> <length of the value> <FNV-1a checksum of the value>
+//! {"eu":1}
+//! ```
+//!
+//! Snapshots are written to a new temporary file in the same directory, synced, and renamed
+//! over the previous snapshot. Readers therefore never see a partially written snapshot unless the
+//! file system does not provide atomic renames.
+
+use {
+    crate::{AtomicNmt, Builder},
+    serde::{de::DeserializeOwned, Serialize},
+    std::{
+        error::Error,
+        ffi::OsString,
+        fmt::{self, Debug, Display, Formatter},
+        fs::{self, File, OpenOptions},
+        io::{self, Write},
+        ops::Deref,
+        path::{Path, PathBuf},
+        process,
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            Arc,
+        },
+    },
+};
+
+/// The first word of the header.
+const MAGIC: &str = "lazy-atomic-snapshot";
+/// The version of the file format.
+const FORMAT: u32 = 1;
+
+/// An atomic whose value is restored from and written to a file.
+///
+/// `Persisted` dereferences to the atomic. Calling `set` on the atomic itself also writes the
+/// snapshot but ignores errors.
+pub struct Persisted<A> {
+    atomic: A,
+    path: PathBuf,
+}
+
+impl<T> Persisted<AtomicNmt<T>>
+where
+    T: Clone + Send + Sync + Serialize + DeserializeOwned + 'static,
+{
+    /// Restores the atomic from the snapshot at `path` or creates it with `default` if there
+    /// is no snapshot.
+    ///
+    /// This is a shorthand for `Persisted::with_builder(path, &AtomicNmt::builder(), default)`.
+    pub fn open(path: impl Into<PathBuf>, default: T) -> Result<Self, PersistError> {
+        Self::with_builder(path, &AtomicNmt::builder(), default)
+    }
+
+    /// Like [`Self::open`] but builds the atomic with `builder`.
+    ///
+    /// The atomic is always [`serializable`](Builder::serializable). The restored value keeps
+    /// the version it had when the snapshot was written. A snapshot that cannot be read is an
+    /// error. It is not replaced with `default`.
+    pub fn with_builder(
+        path: impl Into<PathBuf>,
+        builder: &Builder<T>,
+        default: T,
+    ) -> Result<Self, PersistError> {
+        let path = path.into();
+        let (version, value) = match fs::read(&path) {
+            Ok(bytes) => {
+                let (version, json) = decode(&bytes)?;
+                let value = serde_json::from_slice(json).map_err(PersistError::Deserialize)?;
+                (version, value)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, default),
+            Err(e) => return Err(PersistError::Io(e)),
+        };
+        let mut builder = builder.clone().serializable();
+        builder.initial_version = version;
+        builder.on_set = Some(Arc::new({
+            let path = path.clone();
+            move |value: &T, version| {
+                let json = serde_json::to_vec(value)?;
+                write_atomically(&path, &encode(version, &json))
+            }
+        }));
+        Ok(Self {
+            atomic: builder.build(value),
+            path,
+        })
+    }
+
+    /// Writes the snapshot, sets the value and returns its version.
+    ///
+    /// The value is not set if the snapshot cannot be written. Unlike [`AtomicNmt::set`], a
+    /// concurrent `set` is not dropped. Each `set` waits until the snapshots of the others
+    /// have been written.
+    pub fn set(&self, value: T) -> Result<u64, PersistError> {
+        let version = self.atomic.inner.try_set(value).map_err(PersistError::Io)?;
+        Ok(version.expect("sets of persisted atomics are not dropped"))
+    }
+
+    /// Returns the version of the value. The version is incremented by every `set` and
+    /// survives restarts.
+    pub fn version(&self) -> u64 {
+        self.atomic.inner.generation.load(Relaxed)
+    }
+}
+
+impl<A> Persisted<A> {
+    /// Returns the path of the snapshot.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the atomic.
+    pub fn into_inner(self) -> A {
+        self.atomic
+    }
+}
+
+impl<A> Deref for Persisted<A> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        &self.atomic
+    }
+}
+
+impl<A: Debug> Debug for Persisted<A> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Persisted")
+            .field("atomic", &self.atomic)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// An error returned by [`Persisted`].
+#[derive(Debug)]
+pub enum PersistError {
+    /// The snapshot could not be read or written.
+    ///
+    /// Values that cannot be serialized are reported as [`io::ErrorKind::InvalidData`].
+    Io(io::Error),
+    /// The snapshot does not start with a valid header.
+    InvalidHeader,
+    /// The length of the value does not match the header. The snapshot is incomplete or has
+    /// been modified.
+    LengthMismatch { expected: usize, actual: usize },
+    /// The checksum of the value does not match the header.
+    ChecksumMismatch,
+    /// The value could not be converted to its type.
+    Deserialize(serde_json::Error),
+}
+
+impl Display for PersistError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Io(e) => write!(f, "could not access the snapshot: {}", e),
+            PersistError::InvalidHeader => write!(f, "the snapshot has an invalid header"),
+            PersistError::LengthMismatch { expected, actual } => write!(
+                f,
+                "the snapshot is corrupt: expected {} bytes but found {}",
+                expected, actual
+            ),
+            PersistError::ChecksumMismatch => write!(f, "the snapshot is corrupt: bad checksum"),
+            PersistError::Deserialize(e) => write!(f, "could not deserialize the snapshot: {}", e),
+        }
+    }
+}
+
+impl Error for PersistError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PersistError::Io(e) => Some(e),
+            PersistError::Deserialize(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn encode(version: u64, json: &[u8]) -> Vec<u8> {
+    let mut bytes = format!(
+        "{} {} {} {} {:016x}\n",
+        MAGIC,
+        FORMAT,
+        version,
+        json.len(),
+        checksum(json)
+    )
+    .into_bytes();
+    bytes.extend_from_slice(json);
+    bytes
+}
+
+/// Returns the version and the JSON value of a snapshot.
+fn decode(bytes: &[u8]) -> Result<(u64, &[u8]), PersistError> {
+    let newline = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or(PersistError::InvalidHeader)?;
+    let header = std::str::from_utf8(&bytes[..newline]).map_err(|_| PersistError::InvalidHeader)?;
+    let json = &bytes[newline + 1..];
+    let fields: Vec<_> = header.split(' ').collect();
+    let (version, len, sum) = match fields.as_slice() {
+        [MAGIC, format, version, len, sum] if format.parse::<u32>() == Ok(FORMAT) => (
+            version.parse().map_err(|_| PersistError::InvalidHeader)?,
+            len.parse().map_err(|_| PersistError::InvalidHeader)?,
+            u64::from_str_radix(sum, 16).map_err(|_| PersistError::InvalidHeader)?,
+        ),
+        _ => return Err(PersistError::InvalidHeader),
+    };
+    if json.len() != len {
+        return Err(PersistError::LengthMismatch {
+            expected: len,
+            actual: json.len(),
+        });
+    }
+    if checksum(json) != sum {
+        return Err(PersistError::ChecksumMismatch);
+    }
+    Ok((version, json))
+}
+
+/// Creates a temporary file for `path` in `dir`. The name is unique so that processes and
+/// instances that write the same snapshot do not overwrite each other's temporary files.
+fn create_tmp(dir: &Path, path: &Path) -> io::Result<(PathBuf, File)> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(path.file_name().unwrap_or_default());
+        tmp_name.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            NEXT.fetch_add(1, Relaxed)
+        ));
+        let tmp = dir.join(tmp_name);
+        // NOTE: `create_new` fails if the file exists, even if it is a symlink.
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => return Ok((tmp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Replaces the file at `path` with `bytes`. The file either has its old or its new contents
+/// after a crash. Returns an error only if the file still has its old contents.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (tmp, mut file) = create_tmp(dir, path)?;
+    let result = file
+        .write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+    // NOTE: Sync the directory so that the rename itself survives a crash. The new snapshot
+    // is already visible, so the value has to be set even if this fails.
+    if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(path = %path.display(), error = %e, "could not sync the directory of the snapshot");
+        #[cfg(not(feature = "tracing"))]
+        let _ = e;
+    }
+    Ok(())
+}
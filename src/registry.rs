@@ -0,0 +1,146 @@
+//! A process-wide registry of named atomics.
+//!
+//! Instances created with [`AtomicNmt::named`](crate::AtomicNmt::named) or
+//! [`Builder::name`](crate::Builder::name) are added to the registry. The registry only holds
+//! weak references. An instance is removed once all of its clones have been dropped.
+//!
+//! With the `serde` feature, the values of instances built with
+//! [`Builder::serializable`](crate::Builder::serializable) can be read and written as JSON
+//! without knowing their type. `AtomicNmt::named` does not make an instance serializable.
+
+#[cfg(feature = "serde")]
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+};
+use {
+    crate::nmt::{
+        inner::registry::{self, Instance},
+        inspect::InstanceReport,
+    },
+    std::{
+        fmt::{Debug, Formatter},
+        sync::Arc,
+        time::SystemTime,
+    },
+};
+
+/// A named instance.
+///
+/// An entry keeps the instance alive until it is dropped.
+#[derive(Clone)]
+pub struct Entry(Arc<dyn Instance>);
+
+impl Entry {
+    /// Returns the name of the instance.
+    pub fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    /// Returns the name of the type of the value.
+    pub fn type_name(&self) -> &'static str {
+        self.0.type_name()
+    }
+
+    /// Returns the version of the value. The value passed to `build` has version 0 and every
+    /// `set` that installs its value increments the version. Instances restored by
+    /// `persist::Persisted` continue with the restored version.
+    pub fn version(&self) -> u64 {
+        self.0.version()
+    }
+
+    /// Returns the time of the last `set` or the creation time if the value has never been
+    /// set.
+    pub fn last_set(&self) -> SystemTime {
+        self.0.last_set()
+    }
+
+    /// See [`AtomicNmt::inspect`](crate::AtomicNmt::inspect).
+    pub fn inspect(&self) -> InstanceReport {
+        self.0.clone().inspect()
+    }
+
+    /// Returns the value as JSON.
+    #[cfg(feature = "serde")]
+    pub fn read(&self) -> Result<serde_json::Value, RegistryError> {
+        self.0.clone().read()
+    }
+
+    /// Sets the value from JSON and returns its version.
+    ///
+    /// Returns [`RegistryError::Superseded`] if another thread was setting the value at the
+    /// same time.
+    #[cfg(feature = "serde")]
+    pub fn write(&self, value: serde_json::Value) -> Result<u64, RegistryError> {
+        self.0.clone().write(value)
+    }
+}
+
+impl Debug for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entry")
+            .field("name", &self.name())
+            .field("type_name", &self.type_name())
+            .field("version", &self.version())
+            .field("last_set", &self.last_set())
+            .finish()
+    }
+}
+
+/// Returns all named instances that are alive, in the order in which they were created.
+pub fn entries() -> Vec<Entry> {
+    registry::instances().into_iter().map(Entry).collect()
+}
+
+/// Returns the instances with the given name. Names do not have to be unique.
+pub fn find(name: &str) -> Vec<Entry> {
+    registry::instances()
+        .into_iter()
+        .filter(|instance| instance.name() == name)
+        .map(Entry)
+        .collect()
+}
+
+/// An error returned by [`Entry::read`] and [`Entry::write`].
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum RegistryError {
+    /// The instance has not been built with [`Builder::serializable`](crate::Builder::serializable).
+    NotSerializable,
+    /// The value could not be converted to JSON.
+    Serialize(serde_json::Error),
+    /// The JSON value could not be converted to the type of the value.
+    Deserialize(serde_json::Error),
+    /// The instance is persisted and the value could not be written to disk. The value has
+    /// not been set.
+    Persist(io::Error),
+    /// Another thread was setting the value at the same time. The value has been dropped.
+    Superseded,
+}
+
+#[cfg(feature = "serde")]
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::NotSerializable => write!(f, "the value is not serializable"),
+            RegistryError::Serialize(e) => write!(f, "could not serialize the value: {}", e),
+            RegistryError::Deserialize(e) => write!(f, "could not deserialize the value: {}", e),
+            RegistryError::Persist(e) => write!(f, "could not persist the value: {}", e),
+            RegistryError::Superseded => {
+                write!(f, "the value was dropped because of a concurrent set")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error for RegistryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RegistryError::NotSerializable | RegistryError::Superseded => None,
+            RegistryError::Serialize(e) | RegistryError::Deserialize(e) => Some(e),
+            RegistryError::Persist(e) => Some(e),
+        }
+    }
+}
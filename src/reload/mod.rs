@@ -0,0 +1,442 @@
+//! Keeping an atomic in sync with a file.
+//!
+//! [`watch_file`] parses a file into an [`AtomicNmt`] and sets the atomic whenever the file
+//! changes. Changes are detected with inotify on the directory of the file and on the
+//! directory of its symlink target. This covers files that are written in place, files that
+//! are replaced by renaming another file over them, and symlinks that are swapped, for example
+//! by Kubernetes when it updates a mounted `ConfigMap`.
+//!
+//! Events for other files in these directories cause the file to be read again but it is only
+//! parsed if its contents have changed.
+//!
+//! Values that come from elsewhere, for example from DNS or from a local agent, can be
+//! refreshed periodically with [`Refresher`] or whenever the process receives a signal with
+//! [`reload_on_signal`]. All of these run on a single scheduler thread. A slow refresh
+//! therefore delays the others. In the child of a `fork`, the refreshers of the parent stop
+//! and new refreshers spawn the scheduler thread again.
+
+pub(crate) use schedule::{lock_scheduler, unlock_scheduler};
+pub use schedule::{reload_on_signal, RefreshHandle, Refresher};
+mod schedule;
+
+use {
+    crate::{AtomicNmt, Builder},
+    parking_lot::Mutex,
+    std::{
+        error::Error,
+        ffi::CString,
+        fmt::{self, Debug, Display, Formatter},
+        fs, io,
+        ops::Deref,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::ffi::OsStrExt,
+        },
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+/// The events that cause the file to be read again.
+const MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// Parses the file at `path` into an atomic and keeps the atomic in sync with the file.
+///
+/// This is a shorthand for `FileWatcher::new(path).start(&AtomicNmt::builder(), parse)`.
+pub fn watch_file<T, E>(
+    path: impl Into<PathBuf>,
+    parse: impl Fn(&[u8]) -> Result<T, E> + Send + 'static,
+) -> Result<Reloader<T, E>, ReloadError<E>>
+where
+    T: Clone + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    FileWatcher::new(path).start(&AtomicNmt::builder(), parse)
+}
+
+/// A builder for [`Reloader`].
+#[derive(Clone, Debug)]
+pub struct FileWatcher {
+    path: PathBuf,
+    debounce: Duration,
+}
+
+impl FileWatcher {
+    /// Creates a watcher for the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            debounce: Duration::from_millis(100),
+        }
+    }
+
+    /// Sets how long the file must be left alone before it is read again.
+    ///
+    /// Bursts of events, for example from a writer that writes the file in several chunks,
+    /// only cause a single reload. The default is 100ms.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Parses the file, builds the atomic with `builder` and watches the file on a background
+    /// thread.
+    ///
+    /// Returns an error if the file cannot be read or parsed. Later errors are reported by
+    /// [`Reloader::last_error`] and do not change the value.
+    pub fn start<T, E>(
+        self,
+        builder: &Builder<T>,
+        parse: impl Fn(&[u8]) -> Result<T, E> + Send + 'static,
+    ) -> Result<Reloader<T, E>, ReloadError<E>>
+    where
+        T: Clone + Send + Sync + 'static,
+        E: Send + Sync + 'static,
+    {
+        // NOTE: Watch before the first read. Otherwise a change in between would be missed.
+        let mut watches = Watches::new(&self.path).map_err(ReloadError::Io)?;
+        let contents = fs::read(&self.path).map_err(ReloadError::Io)?;
+        let atomic = builder.build(parse(&contents).map_err(ReloadError::Parse)?);
+        let stop = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if stop == -1 {
+            return Err(ReloadError::Io(io::Error::last_os_error()));
+        }
+        let stop = unsafe { OwnedFd::from_raw_fd(stop) };
+        let state = Arc::new(State::new(&atomic));
+        let thread = {
+            let atomic = atomic.clone();
+            let state = state.clone();
+            let stop = stop.try_clone().map_err(ReloadError::Io)?;
+            let mut last = contents;
+            thread::Builder::new()
+                .name("la-reload".to_string())
+                .spawn(move || {
+                    let mut retry = false;
+                    while watches.wait(&stop, self.debounce, retry) {
+                        watches.follow();
+                        retry = !reload(&state, &self.path, &mut last, &atomic, &parse);
+                    }
+                })
+                .map_err(ReloadError::Io)?
+        };
+        Ok(Reloader {
+            atomic,
+            state,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// An atomic that is kept in sync with a file. The file is no longer watched once this is
+/// dropped.
+///
+/// `Reloader` dereferences to the atomic. Clones of the atomic are not updated after the
+/// reloader has been dropped.
+pub struct Reloader<T: Send + Sync, E> {
+    atomic: AtomicNmt<T>,
+    state: Arc<State<ReloadError<E>>>,
+    stop: OwnedFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Send + Sync, E> Reloader<T, E> {
+    /// Returns the error of the last reload, or `None` if the last reload succeeded.
+    pub fn last_error(&self) -> Option<Arc<ReloadError<E>>> {
+        self.state.last_error()
+    }
+
+    /// Returns the version of the atomic after the last successful reload.
+    ///
+    /// This is the version of the value that was parsed from the file. Values set by other
+    /// means have higher versions.
+    pub fn last_good_version(&self) -> u64 {
+        self.state.last_good_version()
+    }
+}
+
+impl<T: Send + Sync, E> Deref for Reloader<T, E> {
+    type Target = AtomicNmt<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.atomic
+    }
+}
+
+impl<T: Send + Sync, E: Debug> Debug for Reloader<T, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reloader")
+            .field("last_error", &self.last_error())
+            .field("last_good_version", &self.last_good_version())
+            .finish()
+    }
+}
+
+impl<T: Send + Sync, E> Drop for Reloader<T, E> {
+    fn drop(&mut self) {
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.stop.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            );
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// An error returned by [`FileWatcher::start`] and [`Reloader::last_error`].
+#[derive(Debug)]
+pub enum ReloadError<E> {
+    /// The file could not be read or watched.
+    Io(io::Error),
+    /// The file could not be parsed.
+    Parse(E),
+}
+
+impl<E: Display> Display for ReloadError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Io(e) => write!(f, "could not read the file: {}", e),
+            ReloadError::Parse(e) => write!(f, "could not parse the file: {}", e),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ReloadError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReloadError::Io(e) => Some(e),
+            ReloadError::Parse(e) => Some(e),
+        }
+    }
+}
+
+/// The outcome of the last reload. Shared between a handle and the thread that reloads the
+/// value.
+struct State<E> {
+    last_error: Mutex<Option<Arc<E>>>,
+    last_good_version: AtomicU64,
+}
+
+impl<E> State<E> {
+    fn new<T: Send + Sync>(atomic: &AtomicNmt<T>) -> Self {
+        Self {
+            last_error: Mutex::new(None),
+            last_good_version: AtomicU64::new(atomic.inner.generation.load(Relaxed)),
+        }
+    }
+
+    fn last_error(&self) -> Option<Arc<E>> {
+        self.last_error.lock().clone()
+    }
+
+    fn last_good_version(&self) -> u64 {
+        self.last_good_version.load(Relaxed)
+    }
+
+    /// Sets the atomic to a value that has been reloaded successfully. Returns the version or
+    /// `None` if the value has been dropped because another thread set the atomic at the same
+    /// time. Fails if the atomic is persisted and the snapshot cannot be written.
+    fn succeeded<T>(&self, atomic: &AtomicNmt<T>, value: T) -> io::Result<Option<u64>>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let version = match atomic.inner.try_set(value)? {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        self.last_good_version.store(version, Relaxed);
+        *self.last_error.lock() = None;
+        Ok(Some(version))
+    }
+
+    fn failed(&self, error: E) {
+        *self.last_error.lock() = Some(Arc::new(error));
+    }
+}
+
+/// Reads the file and sets the atomic if the contents have changed since `last`. Returns false
+/// if the value has not been installed and the file has to be read again.
+fn reload<T, E>(
+    state: &State<ReloadError<E>>,
+    path: &Path,
+    last: &mut Vec<u8>,
+    atomic: &AtomicNmt<T>,
+    parse: &impl Fn(&[u8]) -> Result<T, E>,
+) -> bool
+where
+    T: Clone + Send + Sync + 'static,
+{
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), error = %e, "could not read the file");
+            state.failed(ReloadError::Io(e));
+            return true;
+        }
+    };
+    if contents == *last {
+        return true;
+    }
+    match parse(&contents) {
+        Ok(value) => match state.succeeded(atomic, value) {
+            Ok(Some(version)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(path = %path.display(), version, "reloaded the file");
+                #[cfg(not(feature = "tracing"))]
+                let _ = version;
+                *last = contents;
+            }
+            Ok(None) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(path = %path.display(), "reloaded value dropped, retrying");
+                return false;
+            }
+            Err(e) => {
+                state.failed(ReloadError::Io(e));
+                *last = contents;
+            }
+        },
+        Err(e) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), "could not parse the file");
+            state.failed(ReloadError::Parse(e));
+            *last = contents;
+        }
+    }
+    true
+}
+
+/// The inotify watches on the directory of the file and on the directory of its symlink
+/// target.
+struct Watches {
+    inotify: OwnedFd,
+    path: PathBuf,
+    /// The watch on the directory of `path`.
+    dir: i32,
+    /// The watch on the directory of the target of `path` if it is a different directory.
+    target: Option<(PathBuf, i32)>,
+}
+
+impl Watches {
+    fn new(path: &Path) -> io::Result<Self> {
+        let inotify = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if inotify == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { OwnedFd::from_raw_fd(inotify) };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = add_watch(&inotify, dir)?;
+        let mut watches = Self {
+            inotify,
+            path: path.to_path_buf(),
+            dir,
+            target: None,
+        };
+        watches.follow();
+        Ok(watches)
+    }
+
+    /// Moves the watch on the target directory to the current target of the path.
+    fn follow(&mut self) {
+        let target = fs::canonicalize(&self.path)
+            .ok()
+            .and_then(|target| target.parent().map(Path::to_path_buf));
+        if target.as_ref() == self.target.as_ref().map(|(dir, _)| dir) {
+            return;
+        }
+        if let Some((_, wd)) = self.target.take() {
+            // NOTE: The directory of the path and the target directory can be the same
+            // directory, in which case they share a watch.
+            if wd != self.dir {
+                unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), wd) };
+            }
+        }
+        // NOTE: If the target cannot be watched, for example because it has already been
+        // removed, the next event on the directory of the path tries again.
+        self.target = target.and_then(|dir| {
+            let wd = add_watch(&self.inotify, &dir).ok()?;
+            Some((dir, wd))
+        });
+    }
+
+    /// Waits for an event followed by `debounce` without events. With `retry`, only waits for
+    /// `debounce` without events. Returns false once `stop` has been signaled.
+    fn wait(&self, stop: &OwnedFd, debounce: Duration, retry: bool) -> bool {
+        let debounce = debounce.as_millis().min(i32::MAX as u128) as i32;
+        let mut timeout = match retry {
+            true => debounce,
+            false => -1,
+        };
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.inotify.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: stop.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) };
+            if res == -1 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return false;
+            }
+            if fds[1].revents != 0 {
+                return false;
+            }
+            if res == 0 {
+                return true;
+            }
+            self.drain();
+            timeout = debounce;
+        }
+    }
+
+    /// Discards the pending events. Which file an event was for does not matter.
+    fn drain(&self) {
+        let mut buf = [0u8; 4096];
+        while unsafe {
+            libc::read(
+                self.inotify.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        } > 0
+        {}
+    }
+}
+
+fn add_watch(inotify: &OwnedFd, dir: &Path) -> io::Result<i32> {
+    let dir = CString::new(dir.as_os_str().as_bytes())?;
+    let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), dir.as_ptr(), MASK) };
+    match wd {
+        -1 => Err(io::Error::last_os_error()),
+        wd => Ok(wd),
+    }
+}
@@ -0,0 +1,548 @@
+//! The scheduler thread shared by all refreshers.
+//!
+//! Signals are delivered through a self-pipe. The signal handler only writes the signal
+//! number to the pipe. Everything else happens on the scheduler thread.
+
+use {
+    crate::{nmt::inner::fork, reload::State, AtomicNmt},
+    once_cell::sync::OnceCell,
+    parking_lot::Mutex,
+    std::{
+        cmp::Reverse,
+        collections::{BinaryHeap, HashMap, HashSet},
+        fmt::{self, Debug, Formatter},
+        io, mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        panic::{self, AssertUnwindSafe},
+        process, ptr,
+        sync::{
+            atomic::{AtomicI32, Ordering::Relaxed},
+            Arc,
+        },
+        thread::{self, ThreadId},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+type RunFn = Box<dyn FnMut() -> Outcome + Send>;
+
+/// How long a job triggered by a signal waits before it retries a value that has been dropped
+/// by a concurrent set. Periodic jobs retry at their next attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The result of a job.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Outcome {
+    /// The value has been refreshed.
+    Applied,
+    /// The value has been dropped because of a concurrent set. The job is retried.
+    Dropped,
+    /// The refresh failed or panicked.
+    Failed,
+}
+
+/// The write end of the self-pipe. -1 until the scheduler has been started.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+static SCHEDULER: OnceCell<Scheduler> = OnceCell::new();
+
+/// A builder for a [`RefreshHandle`] that refreshes a value periodically.
+#[derive(Copy, Clone, Debug)]
+pub struct Refresher {
+    interval: Duration,
+    jitter: f64,
+    max_backoff: Option<Duration>,
+}
+
+impl Refresher {
+    /// Creates a refresher that refreshes the value every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: 0.1,
+            max_backoff: None,
+        }
+    }
+
+    /// Sets by how much each interval is randomly lengthened or shortened, as a fraction of
+    /// the interval.
+    ///
+    /// Jitter keeps processes that were started at the same time from refreshing at the same
+    /// time. The value is clamped to `0.0..=1.0`. The default is `0.1`.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the longest time between two attempts after errors.
+    ///
+    /// After `n` consecutive errors, the next attempt is made after `interval * 2^n` but at
+    /// most after `max_backoff`. The default is 8 times the interval.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = Some(max_backoff);
+        self
+    }
+
+    /// Sets `atomic` to the result of `refresh` every interval, starting one interval from
+    /// now.
+    ///
+    /// Errors do not change the value. They are reported by [`RefreshHandle::last_error`].
+    pub fn start<T, E>(
+        self,
+        atomic: &AtomicNmt<T>,
+        refresh: impl FnMut() -> Result<T, E> + Send + 'static,
+    ) -> io::Result<RefreshHandle<E>>
+    where
+        T: Clone + Send + Sync + 'static,
+        E: Send + Sync + 'static,
+    {
+        let scheduler = scheduler()?;
+        let (state, run) = job(atomic, refresh);
+        let mut shared = scheduler.shared.lock();
+        let id = shared.add(Trigger::Every(self), run);
+        let job = shared.jobs[&id].clone();
+        shared
+            .timers
+            .push(Reverse((Instant::now() + self.delay(0, &mut rng()), id)));
+        drop(shared);
+        scheduler.wake();
+        Ok(RefreshHandle { id, job, state })
+    }
+
+    /// Returns the time until the next attempt after `failures` consecutive errors.
+    fn delay(&self, failures: u32, rng: &mut u64) -> Duration {
+        let delay = match failures {
+            0 => self.interval,
+            n => self
+                .interval
+                .saturating_mul(1 << n.min(16))
+                .min(self.max_backoff.unwrap_or(self.interval.saturating_mul(8))),
+        };
+        // xorshift64
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        let random = (*rng >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+}
+
+/// Sets `atomic` to the result of `reload` whenever the process receives `signal`, for
+/// example `libc::SIGHUP`.
+///
+/// This replaces the previous handler of the signal. The handler stays installed after the
+/// handle has been dropped. The signal is then ignored. Signals that arrive while `reload` is
+/// running are coalesced.
+///
+/// Errors do not change the value. They are reported by [`RefreshHandle::last_error`].
+pub fn reload_on_signal<T, E>(
+    atomic: &AtomicNmt<T>,
+    signal: i32,
+    reload: impl FnMut() -> Result<T, E> + Send + 'static,
+) -> io::Result<RefreshHandle<E>>
+where
+    T: Clone + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    let scheduler = scheduler()?;
+    let (state, run) = job(atomic, reload);
+    let mut shared = scheduler.shared.lock();
+    if !shared.signals.contains(&signal) {
+        unsafe { install_handler(signal)? };
+        shared.signals.insert(signal);
+    }
+    let id = shared.add(Trigger::Signal(signal), run);
+    let job = shared.jobs[&id].clone();
+    Ok(RefreshHandle { id, job, state })
+}
+
+/// A value that is refreshed by the scheduler thread. Refreshing stops once this is dropped.
+#[must_use = "refreshing stops when the handle is dropped"]
+pub struct RefreshHandle<E> {
+    id: u64,
+    job: Arc<Job>,
+    state: Arc<State<E>>,
+}
+
+impl<E> RefreshHandle<E> {
+    /// Returns the error of the last refresh, or `None` if the last refresh succeeded.
+    pub fn last_error(&self) -> Option<Arc<E>> {
+        self.state.last_error()
+    }
+
+    /// Returns the version of the atomic after the last successful refresh.
+    pub fn last_good_version(&self) -> u64 {
+        self.state.last_good_version()
+    }
+
+    /// Stops refreshing. Waits for a refresh that is running on another thread to complete.
+    pub fn stop(self) {}
+}
+
+impl<E: Debug> Debug for RefreshHandle<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshHandle")
+            .field("trigger", &self.job.trigger)
+            .field("last_error", &self.last_error())
+            .field("last_good_version", &self.last_good_version())
+            .finish()
+    }
+}
+
+impl<E> Drop for RefreshHandle<E> {
+    fn drop(&mut self) {
+        let scheduler = SCHEDULER.get().unwrap();
+        let mut shared = scheduler.shared.lock();
+        shared.jobs.remove(&self.id);
+        shared.failures.remove(&self.id);
+        let on_scheduler = shared.thread == Some(thread::current().id());
+        drop(shared);
+        // NOTE: In the child of a `fork`, the jobs of the parent have been forgotten. The job
+        // might have been running during the `fork` and its lock is never released.
+        if self.job.pid != process::id() {
+            return;
+        }
+        // NOTE: On the scheduler thread, the job might be the one that is dropping this
+        // handle. It is then dropped by the scheduler once it returns.
+        let run = match on_scheduler {
+            true => self.job.run.try_lock(),
+            false => Some(self.job.run.lock()),
+        };
+        if let Some(mut run) = run {
+            *run = None;
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Trigger {
+    Every(Refresher),
+    Signal(i32),
+}
+
+struct Job {
+    trigger: Trigger,
+    /// The process that created the job.
+    pid: u32,
+    /// `None` once the job has been stopped.
+    run: Mutex<Option<RunFn>>,
+}
+
+impl Job {
+    /// Runs the job. Returns `None` if the job has been stopped. A panic is an error.
+    fn run(&self) -> Option<Outcome> {
+        let mut run = self.run.lock();
+        let run = run.as_mut()?;
+        match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(outcome) => Some(outcome),
+            Err(_) => {
+                #[cfg(feature = "tracing")]
+                tracing::error!(trigger = ?self.trigger, "refresh panicked");
+                Some(Outcome::Failed)
+            }
+        }
+    }
+}
+
+/// Creates the state and the job that sets `atomic` to the result of `refresh`.
+fn job<T, E>(
+    atomic: &AtomicNmt<T>,
+    mut refresh: impl FnMut() -> Result<T, E> + Send + 'static,
+) -> (Arc<State<E>>, RunFn)
+where
+    T: Clone + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    let state = Arc::new(State::new(atomic));
+    let run = {
+        let atomic = atomic.clone();
+        let state = state.clone();
+        Box::new(move || match refresh() {
+            // NOTE: A persisted atomic reports snapshots that cannot be written itself.
+            Ok(value) => match state.succeeded(&atomic, value) {
+                Ok(Some(_)) => Outcome::Applied,
+                Ok(None) => Outcome::Dropped,
+                Err(_) => Outcome::Failed,
+            },
+            Err(e) => {
+                state.failed(e);
+                Outcome::Failed
+            }
+        })
+    };
+    (state, run)
+}
+
+struct Scheduler {
+    shared: Mutex<Shared>,
+    /// An eventfd that wakes up the scheduler thread when a timer has been added.
+    wake: OwnedFd,
+    /// The read end of the self-pipe.
+    signals: OwnedFd,
+}
+
+struct Shared {
+    /// The scheduler thread. `None` in the child of a `fork` until the thread has been spawned
+    /// again.
+    thread: Option<ThreadId>,
+    next_id: u64,
+    jobs: HashMap<u64, Arc<Job>>,
+    /// The next attempts of the periodic jobs. Entries of removed jobs are skipped.
+    timers: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// The signals whose handler has been installed.
+    signals: HashSet<i32>,
+    /// The consecutive errors of the periodic jobs.
+    failures: HashMap<u64, u32>,
+}
+
+impl Shared {
+    fn add(&mut self, trigger: Trigger, run: RunFn) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let job = Job {
+            trigger,
+            pid: process::id(),
+            run: Mutex::new(Some(run)),
+        };
+        self.jobs.insert(id, Arc::new(job));
+        id
+    }
+}
+
+impl Scheduler {
+    fn new() -> io::Result<Self> {
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let (signals, signal_pipe) = unsafe { (OwnedFd::from_raw_fd(pipe[0]), pipe[1]) };
+        // NOTE: The write end is never closed. Signal handlers use it until the process
+        // exits.
+        SIGNAL_PIPE.store(signal_pipe, Relaxed);
+        Ok(Self {
+            shared: Mutex::new(Shared {
+                thread: None,
+                next_id: 0,
+                jobs: HashMap::new(),
+                timers: BinaryHeap::new(),
+                signals: HashSet::new(),
+                failures: HashMap::new(),
+            }),
+            wake,
+            signals,
+        })
+    }
+
+    fn wake(&self) {
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.wake.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            );
+        }
+    }
+}
+
+/// Returns the scheduler and spawns its thread if necessary.
+fn scheduler() -> io::Result<&'static Scheduler> {
+    let scheduler = SCHEDULER.get_or_try_init(Scheduler::new)?;
+    let mut shared = scheduler.shared.lock();
+    if shared.thread.is_none() {
+        let thread = thread::Builder::new()
+            .name("la-refresh".to_string())
+            .spawn(run)?;
+        shared.thread = Some(thread.thread().id());
+    }
+    Ok(scheduler)
+}
+
+/// Locks the scheduler before a `fork`.
+pub(crate) fn lock_scheduler() {
+    if let Some(scheduler) = SCHEDULER.get() {
+        mem::forget(scheduler.shared.lock());
+    }
+}
+
+/// Unlocks the scheduler after a `fork`. In the child, the scheduler thread no longer exists.
+/// The jobs of the parent are forgotten and the thread is spawned again by the next job.
+///
+/// # Safety
+///
+/// `lock_scheduler` must have been called by this thread.
+pub(crate) unsafe fn unlock_scheduler(child: bool) {
+    if let Some(scheduler) = SCHEDULER.get() {
+        if child {
+            let shared = &mut *scheduler.shared.data_ptr();
+            // NOTE: The jobs might be locked by the scheduler thread.
+            mem::forget(mem::take(&mut shared.jobs));
+            shared.timers.clear();
+            shared.failures.clear();
+            shared.thread = None;
+            // NOTE: The eventfd and the self-pipe are shared with the parent. Its scheduler
+            // thread would consume the wake-ups and signals of the child.
+            let wake = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+            replace_fd(scheduler.wake.as_raw_fd(), wake);
+            let mut pipe = [-1; 2];
+            libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK);
+            replace_fd(scheduler.signals.as_raw_fd(), pipe[0]);
+            replace_fd(SIGNAL_PIPE.load(Relaxed), pipe[1]);
+            fork::unlock_in_child(&scheduler.shared);
+        } else {
+            scheduler.shared.force_unlock();
+        }
+    }
+}
+
+/// Makes `fd` refer to the file of `new` and closes `new`. Keeps `fd` if `new` is -1.
+unsafe fn replace_fd(fd: RawFd, new: RawFd) {
+    if new != -1 {
+        libc::dup3(new, fd, libc::O_CLOEXEC);
+        libc::close(new);
+    }
+}
+
+/// Returns a seed for the jitter.
+fn rng() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_nanos() as u64 ^ std::process::id() as u64) | 1
+}
+
+/// The scheduler thread.
+fn run() {
+    let scheduler = SCHEDULER.wait();
+    let (wake, signals) = (scheduler.wake.as_raw_fd(), &scheduler.signals);
+    let mut rng = rng();
+    loop {
+        let timeout = match scheduler.shared.lock().timers.peek() {
+            Some(Reverse((deadline, _))) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                // Round up. Otherwise the thread wakes up just before the deadline.
+                (timeout.as_nanos().div_ceil(1_000_000)).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        let mut fds = [
+            libc::pollfd {
+                fd: wake,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: signals.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) };
+        let mut buf = [0u8; 64];
+        unsafe { libc::read(wake, buf.as_mut_ptr() as *mut libc::c_void, 8) };
+        let mut received = HashSet::new();
+        loop {
+            let n = unsafe {
+                libc::read(
+                    signals.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n <= 0 {
+                break;
+            }
+            received.extend(buf[..n as usize].iter().map(|&signal| signal as i32));
+        }
+        let due: Vec<_> = {
+            let mut shared = scheduler.shared.lock();
+            let now = Instant::now();
+            let mut due = vec![];
+            while let Some(&Reverse((deadline, id))) = shared.timers.peek() {
+                if deadline > now {
+                    break;
+                }
+                shared.timers.pop();
+                due.extend(shared.jobs.get(&id).map(|job| (id, job.clone())));
+            }
+            due.extend(
+                shared
+                    .jobs
+                    .iter()
+                    .filter_map(|(&id, job)| match job.trigger {
+                        Trigger::Signal(signal) if received.contains(&signal) => {
+                            Some((id, job.clone()))
+                        }
+                        _ => None,
+                    }),
+            );
+            due
+        };
+        for (id, job) in due {
+            let outcome = match job.run() {
+                Some(outcome) => outcome,
+                None => continue,
+            };
+            #[cfg(feature = "tracing")]
+            if outcome == Outcome::Dropped {
+                tracing::debug!(trigger = ?job.trigger, "refreshed value dropped, retrying");
+            }
+            let mut shared = scheduler.shared.lock();
+            if !shared.jobs.contains_key(&id) {
+                shared.failures.remove(&id);
+                continue;
+            }
+            let failures = shared.failures.get(&id).copied().unwrap_or_default();
+            let delay = match (outcome, job.trigger) {
+                // NOTE: A dropped value neither resets nor increases the backoff. Jobs
+                // triggered by signals are retried with a timer.
+                (Outcome::Dropped, Trigger::Every(refresher)) => {
+                    refresher.delay(failures, &mut rng)
+                }
+                (Outcome::Dropped, Trigger::Signal(_)) => RETRY_DELAY,
+                (Outcome::Applied, Trigger::Every(refresher)) => {
+                    shared.failures.insert(id, 0);
+                    refresher.delay(0, &mut rng)
+                }
+                (Outcome::Failed, Trigger::Every(refresher)) => {
+                    shared.failures.insert(id, failures + 1);
+                    refresher.delay(failures + 1, &mut rng)
+                }
+                (Outcome::Applied | Outcome::Failed, Trigger::Signal(_)) => continue,
+            };
+            shared.timers.push(Reverse((Instant::now() + delay, id)));
+        }
+    }
+}
+
+/// Installs the handler that forwards `signal` to the scheduler thread.
+unsafe fn install_handler(signal: i32) -> io::Result<()> {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = on_signal as *const () as usize;
+    action.sa_flags = libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+    match libc::sigaction(signal, &action, ptr::null_mut()) {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // NOTE: Only async-signal-safe functions may be called here. `write` might change
+    // `errno` of the interrupted thread.
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = signal as u8;
+        libc::write(
+            SIGNAL_PIPE.load(Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
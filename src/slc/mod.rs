@@ -1,5 +1,6 @@
 use {
     crate::nmt::{
+        builder::Builder,
         inner::Inner,
         versioning::{Versioned, VersioningU64},
     },
@@ -22,7 +23,7 @@ where
                 version: 0,
                 value: value.clone(),
             },
-            inner: Arc::new(Inner::new(value)),
+            inner: Arc::new(Inner::new(value, &Builder::<T>::default())),
         }
     }
 
@@ -0,0 +1,162 @@
+//! Statistics
+//!
+//! The counters returned by [`snapshot`] and [`AtomicNmt::stats`](crate::AtomicNmt::stats) are
+//! only maintained with the `stats` feature, which is enabled by default. Without it, they
+//! are always 0.
+//!
+//! [`render_openmetrics`] writes all statistics in a format that can be scraped by Prometheus.
+
+pub use openmetrics::render_openmetrics;
+mod openmetrics;
+
+use std::{
+    ops::{Add, AddAssign},
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+pub(crate) static NUM_OFF_CPU_RELEASE: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static NUM_HELPER_AFFINITY_LOST: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static NUM_INLINE_DROPS: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static NUM_BACKGROUND_DROPS: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static NUM_CUSTOM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+/// How often the release of an object had to be deferred due to thread migration.
+///
+/// Deferred releases are performed by the next `get` or `set` on the cpu that owns the
+/// object. This should usually happen in <0.1% of `get` calls.
+pub fn num_off_cpu_release() -> u64 {
+    NUM_OFF_CPU_RELEASE.load(Relaxed) as _
+}
+
+/// How often a per-cpu thread found itself running on the wrong cpu before running a task.
+///
+/// This happens if a cpu is unplugged or removed from the cpuset of the process, or if the
+/// affinity of the thread is changed by someone else.
+pub fn num_helper_affinity_lost() -> u64 {
+    NUM_HELPER_AFFINITY_LOST.load(Relaxed) as _
+}
+
+/// How many values have been dropped with [`DropPolicy::Inline`](crate::DropPolicy::Inline).
+pub fn num_inline_drops() -> u64 {
+    NUM_INLINE_DROPS.load(Relaxed) as _
+}
+
+/// How many values have been sent to a thread with
+/// [`DropPolicy::Background`](crate::DropPolicy::Background).
+pub fn num_background_drops() -> u64 {
+    NUM_BACKGROUND_DROPS.load(Relaxed) as _
+}
+
+/// How many values have been passed to a function with
+/// [`DropPolicy::Custom`](crate::DropPolicy::Custom).
+pub fn num_custom_drops() -> u64 {
+    NUM_CUSTOM_DROPS.load(Relaxed) as _
+}
+
+/// The number of per-cpu objects that are alive.
+///
+/// Each instance owns one per-cpu object per cpu plus the objects that are still referenced
+/// by readers or by deferred releases. After all instances have been dropped and all
+/// deferred releases have been performed, this is 0.
+#[cfg(feature = "debug-refcount")]
+pub fn live_per_cpu_objects() -> usize {
+    crate::nmt::inner::live_per_cpu_objects()
+}
+
+/// The number of releases that are currently deferred to `cpu`.
+///
+/// If this number grows too large, the per-cpu thread of `cpu` performs the releases.
+pub fn deferred_release_queue_depth(cpu: usize) -> usize {
+    crate::nmt::inner::deferred_release_queue_depth(cpu)
+}
+
+/// Counters of the events of the crate or of a single instance.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counters {
+    /// The number of `set` calls that installed their value.
+    pub sets: u64,
+    /// The number of `set` calls whose value was dropped because another `set` was running
+    /// concurrently.
+    pub sets_dropped: u64,
+    /// The number of times a `get` installed a value stored by `set`.
+    pub updates: u64,
+    /// The number of per-cpu copies that were freed when their last reference was released.
+    pub frees: u64,
+    /// The number of tasks that were sent to a per-cpu thread. Only counted globally. The tasks
+    /// of `AtomicNmt::inspect` are not counted.
+    pub tasks_queued: u64,
+    /// The number of tasks that ran on a per-cpu thread. Only counted globally. The tasks of
+    /// `AtomicNmt::inspect` are not counted.
+    pub tasks_run: u64,
+    /// An estimate of the number of `get` calls that returned a value while a newer value had
+    /// already been set.
+    pub stale_reads: u64,
+}
+
+impl Counters {
+    /// Returns the events that happened between `earlier` and `self`.
+    pub fn diff(&self, earlier: &Self) -> Self {
+        Self {
+            sets: self.sets.wrapping_sub(earlier.sets),
+            sets_dropped: self.sets_dropped.wrapping_sub(earlier.sets_dropped),
+            updates: self.updates.wrapping_sub(earlier.updates),
+            frees: self.frees.wrapping_sub(earlier.frees),
+            tasks_queued: self.tasks_queued.wrapping_sub(earlier.tasks_queued),
+            tasks_run: self.tasks_run.wrapping_sub(earlier.tasks_run),
+            stale_reads: self.stale_reads.wrapping_sub(earlier.stale_reads),
+        }
+    }
+}
+
+impl Add for Counters {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, rhs: Self) {
+        self.sets += rhs.sets;
+        self.sets_dropped += rhs.sets_dropped;
+        self.updates += rhs.updates;
+        self.frees += rhs.frees;
+        self.tasks_queued += rhs.tasks_queued;
+        self.tasks_run += rhs.tasks_run;
+        self.stale_reads += rhs.stale_reads;
+    }
+}
+
+/// The counters at a point in time.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Snapshot {
+    /// The sum of all per-cpu counters.
+    pub total: Counters,
+    /// The counters of each cpu. Events are attributed to the cpu that owns the affected
+    /// per-cpu copy. With [`Indexing::ConcurrencyId`](crate::Indexing::ConcurrencyId), this
+    /// is the concurrency id instead.
+    pub per_cpu: Box<[Counters]>,
+}
+
+impl Snapshot {
+    /// Returns the events that happened between `earlier` and `self`.
+    pub fn diff(&self, earlier: &Self) -> Self {
+        let per_cpu: Box<[Counters]> = self
+            .per_cpu
+            .iter()
+            .zip(earlier.per_cpu.iter())
+            .map(|(now, earlier)| now.diff(earlier))
+            .collect();
+        Self {
+            total: self.total.diff(&earlier.total),
+            per_cpu,
+        }
+    }
+}
+
+/// Returns the counters of all instances combined.
+pub fn snapshot() -> Snapshot {
+    crate::nmt::inner::counters::GLOBAL.snapshot()
+}
@@ -0,0 +1,235 @@
+//! An exporter for the OpenMetrics text format.
+//!
+//! See https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use {
+    crate::{
+        nmt::inner::{self, per_cpu_thread, registry},
+        stats::{self, Counters},
+    },
+    std::{
+        collections::BTreeMap,
+        fmt::Display,
+        io::{self, Write},
+    },
+};
+
+const PREFIX: &str = "lazy_atomic";
+
+/// The name, help text and accessor of a counter family.
+type CounterFamily = (&'static str, &'static str, fn(&Counters) -> u64);
+
+/// The counter families that are reported per cpu and per instance.
+const COUNTERS: [CounterFamily; 7] = [
+    ("sets", "Calls to set that installed their value.", |c| {
+        c.sets
+    }),
+    (
+        "sets_dropped",
+        "Calls to set whose value was dropped because of a concurrent set.",
+        |c| c.sets_dropped,
+    ),
+    (
+        "updates",
+        "Values stored by set that were installed by get.",
+        |c| c.updates,
+    ),
+    ("frees", "Per-cpu copies that were freed.", |c| c.frees),
+    ("tasks_queued", "Tasks sent to a per-cpu thread.", |c| {
+        c.tasks_queued
+    }),
+    ("tasks_run", "Tasks that ran on a per-cpu thread.", |c| {
+        c.tasks_run
+    }),
+    (
+        "stale_reads",
+        "Estimated calls to get that returned an outdated value.",
+        |c| c.stale_reads,
+    ),
+];
+
+/// Writes the statistics of the crate in the OpenMetrics text format.
+///
+/// This includes the counters of [`snapshot`](stats::snapshot), the other counters of this
+/// module, the state of the per-cpu threads and the version, counters and estimated memory
+/// usage of every instance that has been given a [name](crate::Builder::name). Instances with
+/// the same name are added together. When a named instance is dropped, its counters are no
+/// longer included, which monitoring systems treat as a counter reset.
+///
+/// The output can be served as is with the content type
+/// `application/openmetrics-text; version=1.0.0; charset=utf-8`.
+pub fn render_openmetrics(w: &mut impl Write) -> io::Result<()> {
+    let snapshot = stats::snapshot();
+    for (name, help, get) in COUNTERS {
+        family(w, name, "counter", help)?;
+        for (cpu, counters) in snapshot.per_cpu.iter().enumerate() {
+            sample(
+                w,
+                &format!("{}_total", name),
+                &[("cpu", &cpu)],
+                get(counters),
+            )?;
+        }
+    }
+
+    family(
+        w,
+        "off_cpu_releases",
+        "counter",
+        "Releases that had to be deferred to another cpu.",
+    )?;
+    sample(
+        w,
+        "off_cpu_releases_total",
+        &[],
+        stats::num_off_cpu_release(),
+    )?;
+    family(
+        w,
+        "helper_affinity_lost",
+        "counter",
+        "Times a per-cpu thread found itself on the wrong cpu.",
+    )?;
+    sample(
+        w,
+        "helper_affinity_lost_total",
+        &[],
+        stats::num_helper_affinity_lost(),
+    )?;
+    family(w, "drops", "counter", "Old values dropped, by drop policy.")?;
+    for (policy, drops) in [
+        ("inline", stats::num_inline_drops()),
+        ("background", stats::num_background_drops()),
+        ("custom", stats::num_custom_drops()),
+    ] {
+        sample(w, "drops_total", &[("policy", &policy)], drops)?;
+    }
+
+    let num_cpus = inner::num_cpus();
+    family(
+        w,
+        "deferred_release_queue_depth",
+        "gauge",
+        "Releases currently deferred to a cpu.",
+    )?;
+    for cpu in 0..num_cpus {
+        let depth = stats::deferred_release_queue_depth(cpu);
+        sample(w, "deferred_release_queue_depth", &[("cpu", &cpu)], depth)?;
+    }
+    let helpers: Vec<_> = (0..num_cpus)
+        .map(per_cpu_thread::helper_thread_queue)
+        .collect();
+    family(
+        w,
+        "helper_thread_running",
+        "gauge",
+        "Whether the per-cpu thread of a cpu is running.",
+    )?;
+    for (cpu, queue) in helpers.iter().enumerate() {
+        let running = queue.is_some() as u8;
+        sample(w, "helper_thread_running", &[("cpu", &cpu)], running)?;
+    }
+    family(
+        w,
+        "helper_thread_queued_tasks",
+        "gauge",
+        "Tasks waiting for the per-cpu thread of a cpu.",
+    )?;
+    for (cpu, queue) in helpers.iter().enumerate() {
+        let queued = queue.unwrap_or_default();
+        sample(w, "helper_thread_queued_tasks", &[("cpu", &cpu)], queued)?;
+    }
+    #[cfg(feature = "debug-refcount")]
+    {
+        family(
+            w,
+            "live_per_cpu_objects",
+            "gauge",
+            "Per-cpu objects that are alive.",
+        )?;
+        sample(
+            w,
+            "live_per_cpu_objects",
+            &[],
+            stats::live_per_cpu_objects(),
+        )?;
+    }
+
+    let mut instances = BTreeMap::<_, (u64, Counters, usize)>::new();
+    for instance in registry::instances() {
+        let entry = instances.entry(instance.name().to_string()).or_default();
+        entry.0 = entry.0.max(instance.version());
+        entry.1 += instance.counters().total;
+        entry.2 += instance.memory_usage();
+    }
+    family(
+        w,
+        "instance_version",
+        "gauge",
+        "The number of values installed by set.",
+    )?;
+    for (name, (version, _, _)) in &instances {
+        sample(w, "instance_version", &[("instance", name)], version)?;
+    }
+    for (name, help, get) in COUNTERS {
+        // NOTE: Tasks are only counted globally.
+        if name.starts_with("tasks_") {
+            continue;
+        }
+        family(w, &format!("instance_{}", name), "counter", help)?;
+        for (instance, (_, counters, _)) in &instances {
+            let name = format!("instance_{}_total", name);
+            sample(w, &name, &[("instance", instance)], get(counters))?;
+        }
+    }
+    family(
+        w,
+        "instance_memory_bytes",
+        "gauge",
+        "Estimated memory used by the per-cpu copies, excluding memory owned by the values.",
+    )?;
+    writeln!(w, "# UNIT {}_instance_memory_bytes bytes", PREFIX)?;
+    for (name, (_, _, memory)) in &instances {
+        sample(w, "instance_memory_bytes", &[("instance", name)], memory)?;
+    }
+    writeln!(w, "# EOF")
+}
+
+fn family(w: &mut impl Write, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(w, "# TYPE {}_{} {}", PREFIX, name, kind)?;
+    writeln!(w, "# HELP {}_{} {}", PREFIX, name, help)
+}
+
+fn sample(
+    w: &mut impl Write,
+    name: &str,
+    labels: &[(&str, &dyn Display)],
+    value: impl Display,
+) -> io::Result<()> {
+    write!(w, "{}_{}", PREFIX, name)?;
+    for (i, (label, value)) in labels.iter().enumerate() {
+        let sep = match i {
+            0 => "{",
+            _ => ",",
+        };
+        write!(w, "{}{}=\"{}\"", sep, label, escape(&value.to_string()))?;
+    }
+    if !labels.is_empty() {
+        write!(w, "}}")?;
+    }
+    writeln!(w, " {}", value)
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
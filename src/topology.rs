@@ -0,0 +1,181 @@
+//! The cpu topology of the system.
+
+use {
+    once_cell::sync::Lazy,
+    std::{
+        fs, io,
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::Arc,
+    },
+};
+
+/// How many cpus share one copy of a value.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Granularity {
+    /// One copy per cpu.
+    #[default]
+    Cpu,
+    /// One copy per physical core. Hyperthreads of the same core share a copy.
+    Core,
+    /// One copy per last-level cache.
+    Llc,
+    /// One copy per NUMA node.
+    NumaNode,
+}
+
+/// The cpu topology of a system as described by sysfs.
+///
+/// Information that is not available, for example because a cpu is offline, is treated as if
+/// the cpu were not sharing anything with other cpus.
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    /// For each cpu, the lowest cpu of the same core.
+    core: Box<[usize]>,
+    /// For each cpu, the lowest cpu sharing the same last-level cache.
+    llc: Box<[usize]>,
+    /// For each cpu, the lowest cpu of the same NUMA node.
+    numa_node: Box<[usize]>,
+    /// For each cpu, the id of its NUMA node.
+    node_id: Box<[Option<u32>]>,
+}
+
+pub(crate) static SYSTEM: Lazy<Arc<Topology>> =
+    Lazy::new(|| Arc::new(Topology::from_sysfs("/sys").unwrap_or_default()));
+
+impl Topology {
+    /// Reads the topology from a sysfs tree mounted at `root`.
+    ///
+    /// `root` is usually `/sys`. Fails if `<root>/devices/system/cpu/online` cannot be read.
+    pub fn from_sysfs(root: impl AsRef<Path>) -> io::Result<Self> {
+        let cpu_dir = root.as_ref().join("devices/system/cpu");
+        let online = fs::read_to_string(cpu_dir.join("online"))?;
+        let num_cpus = parse_cpu_list(&online)
+            .and_then(|cpus| cpus.last().map(|last| last + 1))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid cpu list"))?;
+        let leader = |cpu: usize, path: PathBuf| {
+            read_cpu_list(&path)
+                .and_then(|cpus| cpus.first().copied())
+                .unwrap_or(cpu)
+        };
+        let node_id = (0..num_cpus)
+            .map(|cpu| node_of(&cpu_dir.join(format!("cpu{}", cpu))))
+            .collect::<Box<[_]>>();
+        Ok(Self {
+            core: (0..num_cpus)
+                .map(|cpu| {
+                    leader(
+                        cpu,
+                        cpu_dir.join(format!("cpu{}/topology/core_cpus_list", cpu)),
+                    )
+                })
+                .collect(),
+            llc: (0..num_cpus)
+                .map(
+                    |cpu| match llc_of(&cpu_dir.join(format!("cpu{}/cache", cpu))) {
+                        Some(path) => leader(cpu, path),
+                        None => cpu,
+                    },
+                )
+                .collect(),
+            numa_node: (0..num_cpus)
+                .map(|cpu| match node_id[cpu] {
+                    Some(node) => leader(
+                        cpu,
+                        root.as_ref()
+                            .join(format!("devices/system/node/node{}/cpulist", node)),
+                    ),
+                    None => cpu,
+                })
+                .collect(),
+            node_id,
+        })
+    }
+
+    /// Returns the number of cpus described by this topology.
+    pub fn num_cpus(&self) -> usize {
+        self.core.len()
+    }
+
+    /// Returns the shard of `cpu` at the given granularity.
+    ///
+    /// The shard is identified by the lowest cpu it contains. If sysfs names a cpu that is not
+    /// described by this topology, `cpu` is its own shard.
+    pub fn shard(&self, cpu: usize, granularity: Granularity) -> usize {
+        let leaders = match granularity {
+            Granularity::Cpu => return cpu,
+            Granularity::Core => &self.core,
+            Granularity::Llc => &self.llc,
+            Granularity::NumaNode => &self.numa_node,
+        };
+        match leaders.get(cpu) {
+            Some(&leader) if leader < self.num_cpus() => leader,
+            _ => cpu,
+        }
+    }
+
+    /// Returns the NUMA node of `cpu`.
+    pub fn numa_node(&self, cpu: usize) -> Option<u32> {
+        self.node_id.get(cpu).copied().flatten()
+    }
+}
+
+/// Returns the path of the `shared_cpu_list` of the last-level cache.
+fn llc_of(cache_dir: &Path) -> Option<PathBuf> {
+    let mut llc = None;
+    for entry in fs::read_dir(cache_dir).ok()? {
+        let path = entry.ok()?.path();
+        let is_index = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with("index"))
+            .unwrap_or(false);
+        if !is_index {
+            continue;
+        }
+        let ty = fs::read_to_string(path.join("type")).ok()?;
+        if ty.trim() == "Instruction" {
+            continue;
+        }
+        let level = fs::read_to_string(path.join("level")).ok()?;
+        let level = u32::from_str(level.trim()).ok()?;
+        if llc.as_ref().map(|(l, _)| level > *l).unwrap_or(true) {
+            llc = Some((level, path.join("shared_cpu_list")));
+        }
+    }
+    llc.map(|(_, path)| path)
+}
+
+/// Returns the NUMA node of a cpu from the `nodeN` entry in its sysfs directory.
+fn node_of(cpu_dir: &Path) -> Option<u32> {
+    for entry in fs::read_dir(cpu_dir).ok()? {
+        let name = entry.ok()?.file_name();
+        let name = name.to_str()?;
+        if let Some(node) = name.strip_prefix("node") {
+            if let Ok(node) = u32::from_str(node) {
+                return Some(node);
+            }
+        }
+    }
+    None
+}
+
+fn read_cpu_list(path: &Path) -> Option<Vec<usize>> {
+    parse_cpu_list(&fs::read_to_string(path).ok()?)
+}
+
+/// Parses a list of the form `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (range, range),
+        };
+        let first = usize::from_str(first).ok()?;
+        let last = usize::from_str(last).ok()?;
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    Some(cpus)
+}
@@ -0,0 +1,56 @@
+//! Checks that an atomic indexed by concurrency id allocates no more copies than there are
+//! threads accessing it.
+//!
+//! This requires Linux 6.3 or later.
+
+use {
+    lazy_atomic::{AtomicNmt, Indexing},
+    std::{sync::Barrier, thread},
+};
+
+const THREADS: usize = 4;
+
+/// Returns the number of copies that have been allocated.
+fn copies(atomic: &AtomicNmt<u64>) -> usize {
+    let report = atomic.inspect();
+    report
+        .per_cpu
+        .iter()
+        .filter(|c| c.version.is_some())
+        .count()
+}
+
+#[test]
+fn concurrency_id() {
+    let cpus = thread::available_parallelism().unwrap().get();
+    let atomic = AtomicNmt::builder()
+        .indexing(Indexing::ConcurrencyId)
+        .build(0u64);
+    let barrier = Barrier::new(THREADS);
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                // NOTE: Keep all threads alive so that they do not reuse each other's ids.
+                barrier.wait();
+                for i in 0..10_000 {
+                    assert!(atomic.get() <= 1);
+                    if i == 5_000 {
+                        atomic.set(1);
+                    }
+                }
+                barrier.wait();
+            });
+        }
+    });
+    assert_eq!(atomic.get(), 1);
+    // The main thread might use an id of its own.
+    let copies = copies(&atomic);
+    assert!(copies >= 1);
+    assert!(
+        copies <= (THREADS + 1).min(cpus),
+        "{} threads on {} cpus allocated {} copies",
+        THREADS,
+        cpus,
+        copies
+    );
+}
@@ -0,0 +1,78 @@
+//! Runs `lazy-atomic-ctl` against the admin socket of this process.
+//!
+//! Run with `--features admin`.
+
+use {
+    lazy_atomic::{admin::AdminServer, AtomicNmt},
+    serde_json::{json, Value},
+    std::{collections::BTreeMap, env, path::PathBuf, process::Command},
+};
+
+/// Runs `lazy-atomic-ctl --socket <socket> <args>` and returns its stdout or its stderr if it
+/// failed.
+fn run(socket: &PathBuf, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_lazy-atomic-ctl"))
+        .arg("--socket")
+        .arg(socket)
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    match output.status.success() {
+        true => Ok(stdout),
+        false => Err(stderr),
+    }
+}
+
+#[test]
+fn ctl() {
+    let socket = env::temp_dir().join(format!("lazy-atomic-ctl-{}.sock", std::process::id()));
+    let _server = AdminServer::new(&socket).start().unwrap();
+    let routes = AtomicNmt::builder()
+        .name("routing.table")
+        .serializable()
+        .build(BTreeMap::from([("eu".to_string(), 1u32)]));
+    let limit = AtomicNmt::builder()
+        .name("rate.limit")
+        .serializable()
+        .build(100u32);
+
+    let list: Value = serde_json::from_str(&run(&socket, &["--json", "list"]).unwrap()).unwrap();
+    assert_eq!(list.as_array().unwrap().len(), 2);
+
+    // Pretty-printed JSON is sent as a single request.
+    let value = "{\n  \"eu\": 2,\n  \"us\": 3\n}";
+    let result = run(&socket, &["--json", "set", "routing.table", value]).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&result).unwrap(),
+        json!({ "version": 1 })
+    );
+    assert_eq!(routes.get()["us"], 3);
+    let value = run(&socket, &["get", "routing.table"]).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&value).unwrap(),
+        json!({ "eu": 2, "us": 3 })
+    );
+
+    // Invalid JSON is rejected without sending it.
+    let error = run(&socket, &["set", "rate.limit", "{\n  250"]).unwrap_err();
+    assert!(error.contains("invalid JSON"), "{}", error);
+    let error = run(&socket, &["set", "rate.limit", "-1"]).unwrap_err();
+    assert!(error.contains("could not deserialize"), "{}", error);
+    assert_eq!(limit.get(), 100);
+
+    // Names containing whitespace would be split by the server.
+    let error = run(&socket, &["set", "rate.limit 250", "1"]).unwrap_err();
+    assert!(error.contains("invalid name"), "{}", error);
+    let error = run(&socket, &["get", "rate.limit\n"]).unwrap_err();
+    assert!(error.contains("invalid name"), "{}", error);
+    assert_eq!(limit.get(), 100);
+
+    let error = run(&socket, &["get", "missing"]).unwrap_err();
+    assert!(
+        error.contains("no instance is named `missing`"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,32 @@
+//! Checks that the alternate `Debug` form of an atomic prints a report without running tasks
+//! on the per-cpu threads.
+
+use {
+    lazy_atomic::{stats, AtomicNmt, Indexing},
+    std::thread,
+};
+
+#[test]
+fn debug_report() {
+    for indexing in [Indexing::Cpu, Indexing::ConcurrencyId] {
+        let atomic = AtomicNmt::builder()
+            .name("config")
+            .indexing(indexing)
+            .build(vec![0u8; 16]);
+        atomic.set(vec![1; 16]);
+        assert_eq!(atomic.get(), [1; 16]);
+
+        let before = stats::snapshot();
+        let pretty = format!("{:#?}", atomic);
+        let tasks = stats::snapshot().diff(&before).total;
+        assert_eq!((tasks.tasks_queued, tasks.tasks_run), (0, 0));
+        assert!(pretty.contains("report: InstanceReport"), "{}", pretty);
+        assert!(pretty.contains("version: 1"), "{}", pretty);
+        let per_cpu = pretty.matches("CpuReport").count();
+        assert_eq!(per_cpu, thread::available_parallelism().unwrap().get());
+
+        // The plain form only prints the value.
+        let plain = format!("{:?}", atomic);
+        assert!(!plain.contains("report"), "{}", plain);
+    }
+}
@@ -0,0 +1,77 @@
+//! Checks that releases deferred to a cpu are performed even if the executor drops the tasks
+//! that drain them.
+//!
+//! Run with `--features debug-refcount`.
+
+use {
+    lazy_atomic::{
+        set_release_executor, stats::live_per_cpu_objects, AtomicNmt, GcTask, ReleaseExecutor,
+    },
+    std::{
+        mem,
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of tasks that have been sent to `Executor`.
+static TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Drops every other task and runs the others on a new thread pinned to their cpu.
+struct Executor;
+
+impl ReleaseExecutor for Executor {
+    fn run_on_cpu(&self, cpu: usize, task: GcTask) {
+        if TASKS.fetch_add(1, SeqCst).is_multiple_of(2) {
+            return;
+        }
+        thread::spawn(move || {
+            pin(cpu);
+            task();
+        });
+    }
+}
+
+fn pin(cpu: usize) {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, mem::size_of_val(&set), &set);
+    }
+}
+
+#[test]
+fn dropped_drain() {
+    assert!(set_release_executor(Box::new(Executor)).is_ok());
+    let cpus = thread::available_parallelism().unwrap().get();
+    for _ in 0..4 {
+        let atomic = AtomicNmt::new(vec![0]);
+        // Create a copy on every cpu.
+        thread::scope(|s| {
+            for cpu in 0..cpus {
+                let atomic = &atomic;
+                s.spawn(move || {
+                    pin(cpu);
+                    assert_eq!(atomic.get(), [0]);
+                });
+            }
+        });
+        // Dropping the atomic on one cpu defers the releases of the copies of the others.
+        thread::spawn(move || {
+            pin(0);
+            drop(atomic);
+        })
+        .join()
+        .unwrap();
+        let start = Instant::now();
+        while live_per_cpu_objects() > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{} releases were not performed",
+                live_per_cpu_objects()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Forks while other threads use an `AtomicNmt` and checks that the child can still use it
+//! without deadlocking or leaking values.
+//!
+//! Runs without the test harness so that the child only contains the forking thread and a
+//! failing assertion makes it exit. Run with `--features debug-refcount,reload` to cover their
+//! global state as well.
+
+#[cfg(feature = "reload")]
+use std::time::Instant;
+use {
+    lazy_atomic::{registry, run_on_cpu, shutdown, AtomicNmt, DropPolicy, DropThread},
+    std::{
+        hint::black_box,
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+const WORKERS: usize = 4;
+const FORKS: usize = 20;
+
+/// The number of `Counted` values that are alive.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+struct Counted(u64);
+
+impl Counted {
+    fn new(v: u64) -> Self {
+        LIVE.fetch_add(1, Relaxed);
+        Self(v)
+    }
+}
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        Self::new(self.0)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, Relaxed);
+    }
+}
+
+fn main() {
+    let atomic = AtomicNmt::new(Counted::new(0));
+    // Spawn the helper thread so that the child inherits it.
+    run_on_cpu(0, Box::new(|| ()));
+    // Old values of this atomic are dropped on a thread that does not exist in the child.
+    let background = AtomicNmt::builder()
+        .name("background")
+        .drop_policy(DropPolicy::Background(
+            DropThread::spawn("dropper").unwrap(),
+        ))
+        .build(Counted::new(0));
+    thread::spawn({
+        let background = background.clone();
+        move || {
+            for i in 0.. {
+                background.set(Counted::new(i));
+                drop(black_box(background.get()));
+                // Keeps the registry busy.
+                drop(black_box(AtomicNmt::named("short-lived", 0)));
+                black_box(registry::entries());
+            }
+        }
+    });
+    #[cfg(feature = "reload")]
+    let _refresh = atomic
+        .refresh_every(Duration::from_millis(1), || Ok::<_, ()>(Counted::new(0)))
+        .unwrap();
+    for i in 0..WORKERS {
+        let atomic = atomic.clone();
+        thread::spawn(move || {
+            for j in 0.. {
+                match j % 16 == 0 {
+                    true => atomic.set(Counted::new(i as u64)),
+                    false => drop(black_box(atomic.get())),
+                }
+            }
+        });
+    }
+    for _ in 0..FORKS {
+        thread::sleep(Duration::from_millis(10));
+        match unsafe { libc::fork() } {
+            -1 => panic!("Could not fork"),
+            0 => child(atomic, background),
+            pid => {
+                let mut status = 0;
+                unsafe {
+                    libc::waitpid(pid, &mut status, 0);
+                }
+                assert!(
+                    libc::WIFEXITED(status),
+                    "The child did not exit: {}",
+                    status
+                );
+                assert_eq!(libc::WEXITSTATUS(status), 0, "The child failed");
+            }
+        }
+    }
+    println!("ok");
+}
+
+fn child(atomic: AtomicNmt<Counted>, background: AtomicNmt<Counted>) -> ! {
+    // Kill the child if it deadlocks.
+    unsafe {
+        libc::alarm(5);
+    }
+    let live = LIVE.load(Relaxed);
+    for i in 1000..2000 {
+        atomic.set(Counted::new(i));
+        assert_eq!(atomic.get().0, i);
+    }
+    // The helper thread must be spawned again.
+    let (tx, rx) = mpsc::channel();
+    run_on_cpu(0, Box::new(move || tx.send(()).unwrap()));
+    rx.recv_timeout(Duration::from_secs(1))
+        .expect("The helper thread did not run");
+    // Values of the background atomic are dropped inline.
+    for i in 0..100 {
+        background.set(Counted::new(i));
+        assert_eq!(background.get().0, i);
+    }
+    assert!(registry::find("background").len() == 1);
+    drop(AtomicNmt::named("child", 0));
+    // The refreshers of the parent stop. New refreshers spawn the scheduler thread again.
+    #[cfg(feature = "reload")]
+    {
+        let refreshed = AtomicNmt::new(0);
+        let _refresh = refreshed
+            .refresh_every(Duration::from_millis(1), || Ok::<_, ()>(1))
+            .unwrap();
+        let start = Instant::now();
+        while refreshed.get() != 1 {
+            assert!(start.elapsed() < Duration::from_secs(1), "not refreshed");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    shutdown();
+    // The copies that existed at the time of the fork must have been freed by `set`. Workers
+    // that were using a copy at the time of the fork never release their reference.
+    assert!(
+        LIVE.load(Relaxed) <= live + WORKERS,
+        "{} values leaked",
+        LIVE.load(Relaxed) - live - WORKERS
+    );
+    drop(atomic);
+    unsafe { libc::_exit(0) }
+}
@@ -0,0 +1,58 @@
+//! Uses atomics with all options from several threads and checks that all per-cpu objects have
+//! been freed after the atomics have been dropped.
+//!
+//! Run with `--features debug-refcount`.
+
+use {
+    lazy_atomic::{
+        pin_current_thread, stats::live_per_cpu_objects, unpin_current_thread, AtomicNmt,
+        AtomicSlc, Indexing, Reclamation,
+    },
+    std::thread,
+};
+
+#[test]
+fn leak_check() {
+    for indexing in [Indexing::Cpu, Indexing::ConcurrencyId] {
+        for reclamation in [Reclamation::Readers, Reclamation::Membarrier] {
+            let atomic = AtomicNmt::builder()
+                .indexing(indexing)
+                .reclamation(reclamation)
+                .build(vec![0]);
+            let threads: Vec<_> = (0..4)
+                .map(|i| {
+                    let atomic = atomic.clone();
+                    thread::spawn(move || {
+                        if i == 0 {
+                            pin_current_thread(0).unwrap();
+                        }
+                        for j in 0..10_000 {
+                            match j % 10 == 0 {
+                                true => atomic.set(vec![j]),
+                                false => drop(atomic.get()),
+                            }
+                        }
+                        unpin_current_thread();
+                    })
+                })
+                .collect();
+            threads.into_iter().for_each(|t| t.join().unwrap());
+            assert!(live_per_cpu_objects() > 0);
+            drop(atomic);
+            // Perform the releases that have been deferred to other cpus.
+            lazy_atomic::shutdown();
+            assert_eq!(
+                live_per_cpu_objects(),
+                0,
+                "{:?} {:?}: per-cpu objects leaked",
+                indexing,
+                reclamation
+            );
+        }
+    }
+    let mut atomic = AtomicSlc::new(1);
+    atomic.set(2);
+    assert_eq!(*atomic.get(), 2);
+    drop(atomic);
+    assert_eq!(live_per_cpu_objects(), 0);
+}
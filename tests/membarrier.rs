@@ -0,0 +1,47 @@
+//! Checks that `set` frees the copies of the old value immediately with
+//! `Reclamation::Membarrier` but not with `Reclamation::Readers`.
+//!
+//! This requires Linux 5.10 or later.
+
+use {
+    lazy_atomic::{AtomicNmt, Reclamation},
+    std::{sync::Arc, thread},
+};
+
+/// A value that shares its token with all of its copies.
+#[derive(Clone)]
+struct Value(Arc<u32>);
+
+/// Returns the number of copies of `token` that are still alive, not counting `token` itself.
+fn copies(token: &Arc<u32>) -> usize {
+    Arc::strong_count(token) - 1
+}
+
+#[test]
+fn membarrier() {
+    for reclamation in [Reclamation::Readers, Reclamation::Membarrier] {
+        let old = Arc::new(1);
+        let atomic = AtomicNmt::builder()
+            .reclamation(reclamation)
+            .build(Value(old.clone()));
+        // Create copies of the old value on the cpus of a few threads.
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| assert_eq!(*atomic.get().0, 1));
+            }
+        });
+        assert!(copies(&old) > 0);
+        atomic.set(Value(Arc::new(2)));
+        match reclamation {
+            Reclamation::Readers => assert!(copies(&old) > 0),
+            Reclamation::Membarrier => assert_eq!(copies(&old), 0),
+        }
+        // With `Readers`, the copies are freed by the next `get` on their cpu.
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| assert_eq!(*atomic.get().0, 2));
+            }
+        });
+        assert_eq!(*atomic.get().0, 2);
+    }
+}
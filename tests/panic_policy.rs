@@ -0,0 +1,65 @@
+//! Checks that panics of tasks on the helper threads are reported to the hook and handled
+//! according to the `PanicPolicy`.
+//!
+//! Runs without the test harness because the config can only be installed once per process
+//! and the check of `PanicPolicy::Abort` runs this binary again in a child process.
+
+use {
+    lazy_atomic::{run_on_cpu, Config, PanicPolicy},
+    std::{
+        env, os::unix::process::ExitStatusExt, panic, process::Command, sync::mpsc, thread,
+        time::Duration,
+    },
+};
+
+/// Runs a task that panics on the helper thread of cpu 0.
+fn panic_on_helper_thread() {
+    run_on_cpu(0, Box::new(|| panic!("task panicked")));
+}
+
+/// Waits for a task on the helper thread of cpu 0 to run.
+fn task_runs() -> bool {
+    let (tx, rx) = mpsc::channel();
+    run_on_cpu(0, Box::new(move || tx.send(()).unwrap()));
+    rx.recv_timeout(Duration::from_secs(1)).is_ok()
+}
+
+/// Runs in a child process with [`PanicPolicy::Abort`].
+fn abort() {
+    Config::new()
+        .panic_policy(PanicPolicy::Abort)
+        .install()
+        .unwrap();
+    panic_on_helper_thread();
+    thread::sleep(Duration::from_secs(5));
+    unreachable!("the process was not aborted");
+}
+
+fn main() {
+    // NOTE: The default panic hook prints the expected panics.
+    panic::set_hook(Box::new(|_| {}));
+    if env::args().nth(1).as_deref() == Some("abort") {
+        abort();
+    }
+
+    let (tx, rx) = mpsc::sync_channel(1);
+    Config::new()
+        .on_task_panic(move |cpu, payload| {
+            let message = payload.downcast_ref::<&str>().unwrap();
+            tx.send((cpu, message.to_string())).unwrap();
+        })
+        .install()
+        .unwrap();
+    panic_on_helper_thread();
+    let (cpu, message) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!((cpu, &*message), (0, "task panicked"));
+    // The helper thread keeps running.
+    assert!(task_runs());
+
+    let status = Command::new(env::current_exe().unwrap())
+        .arg("abort")
+        .status()
+        .unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGABRT));
+    println!("ok");
+}
@@ -0,0 +1,122 @@
+//! Checks that panicking `Clone` and `Drop` implementations do not leak values or corrupt the
+//! state of an `AtomicNmt`.
+
+use {
+    lazy_atomic::{pin_current_thread, unpin_current_thread, AtomicNmt, Reclamation},
+    std::{
+        panic::{self, AssertUnwindSafe},
+        sync::atomic::{
+            AtomicBool, AtomicIsize, AtomicU64,
+            Ordering::{Relaxed, SeqCst},
+        },
+    },
+};
+
+/// The number of `Value`s that are alive.
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+/// The next clone of a value with this id panics.
+static PANIC_ON_CLONE: AtomicU64 = AtomicU64::new(0);
+/// The next drop of a value with this id panics.
+static PANIC_ON_DROP: AtomicU64 = AtomicU64::new(0);
+/// Whether a panic is expected. Expected panics are not printed.
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+struct Value(u64);
+
+impl Value {
+    fn new(id: u64) -> Self {
+        LIVE.fetch_add(1, SeqCst);
+        Self(id)
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if PANIC_ON_CLONE
+            .compare_exchange(self.0, 0, SeqCst, Relaxed)
+            .is_ok()
+        {
+            panic!("clone of {}", self.0);
+        }
+        Self::new(self.0)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, SeqCst);
+        if PANIC_ON_DROP
+            .compare_exchange(self.0, 0, SeqCst, Relaxed)
+            .is_ok()
+        {
+            panic!("drop of {}", self.0);
+        }
+    }
+}
+
+fn catch(f: impl FnOnce()) {
+    EXPECT_PANIC.store(true, SeqCst);
+    let res = panic::catch_unwind(AssertUnwindSafe(f));
+    EXPECT_PANIC.store(false, SeqCst);
+    assert!(res.is_err(), "No panic");
+}
+
+/// Checks that no value is leaked after `f` has run.
+fn check(name: &str, f: impl FnOnce()) {
+    f();
+    assert_eq!(LIVE.load(SeqCst), 0, "{}: values leaked", name);
+}
+
+fn atomic(reclamation: Reclamation) -> AtomicNmt<Value> {
+    AtomicNmt::builder()
+        .reclamation(reclamation)
+        .build(Value::new(1))
+}
+
+// NOTE: The checks share `LIVE` and the panic hook and therefore run in a single test.
+#[test]
+fn panic_safety() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !EXPECT_PANIC.load(SeqCst) {
+            hook(info);
+        }
+    }));
+    for reclamation in [Reclamation::Readers, Reclamation::Membarrier] {
+        check(&format!("{:?}: clone in get", reclamation), || {
+            let a = atomic(reclamation);
+            PANIC_ON_CLONE.store(1, SeqCst);
+            catch(|| drop(a.get()));
+            assert_eq!(a.get().0, 1);
+        });
+        check(&format!("{:?}: clone in set", reclamation), || {
+            let a = atomic(reclamation);
+            PANIC_ON_CLONE.store(2, SeqCst);
+            catch(|| a.set(Value::new(2)));
+            assert_eq!(a.get().0, 1);
+            a.set(Value::new(3));
+            assert_eq!(a.get().0, 3);
+        });
+        check(&format!("{:?}: drop of old value", reclamation), || {
+            let a = atomic(reclamation);
+            PANIC_ON_DROP.store(1, SeqCst);
+            // Depending on the reclamation, the old value is dropped by `set` or `get`.
+            catch(|| {
+                a.set(Value::new(2));
+                drop(a.get());
+            });
+            assert_eq!(a.get().0, 2);
+            a.set(Value::new(3));
+            assert_eq!(a.get().0, 3);
+        });
+    }
+    check("pinned: clone in get", || {
+        let a = atomic(Reclamation::Readers);
+        pin_current_thread(0).unwrap();
+        PANIC_ON_CLONE.store(1, SeqCst);
+        catch(|| drop(a.get()));
+        assert_eq!(a.get().0, 1);
+        unpin_current_thread();
+    });
+}
@@ -0,0 +1,120 @@
+//! Changes the affinity of pinned readers while they are in the middle of a `get` and checks
+//! that no reference count is corrupted.
+//!
+//! Run with `--features debug-refcount`.
+
+use {
+    lazy_atomic::{
+        pin_current_thread, shutdown, stats::live_per_cpu_objects, unpin_current_thread, AtomicNmt,
+    },
+    std::{
+        mem,
+        sync::atomic::{
+            AtomicBool, AtomicI32, AtomicIsize, AtomicUsize,
+            Ordering::{Relaxed, SeqCst},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of `Value`s that are alive.
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+/// The number of times `Value::clone` has moved the current thread.
+static MOVES: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the affinity of the thread `tid` to `cpu`.
+fn move_to(tid: i32, cpu: usize) {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(tid, mem::size_of_val(&set), &set);
+    }
+}
+
+fn gettid() -> i32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as i32 }
+}
+
+/// A value whose `clone` sometimes moves the current thread to another cpu.
+struct Value(Box<u64>);
+
+impl Value {
+    fn new(id: u64) -> Self {
+        LIVE.fetch_add(1, SeqCst);
+        Self(Box::new(id))
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        let moves = MOVES.fetch_add(1, Relaxed);
+        if moves.is_multiple_of(16) {
+            let cpus = thread::available_parallelism().unwrap().get();
+            move_to(0, moves / 16 % cpus);
+        }
+        thread::yield_now();
+        Self::new(*self.0)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        assert_ne!(*self.0, u64::MAX, "value dropped twice");
+        *self.0 = u64::MAX;
+        LIVE.fetch_sub(1, SeqCst);
+    }
+}
+
+#[test]
+fn pinned_migration() {
+    let cpus = thread::available_parallelism().unwrap().get();
+    let atomic = AtomicNmt::new(Value::new(0));
+    let stop = AtomicBool::new(false);
+    let reader_tid = AtomicI32::new(0);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            reader_tid.store(gettid(), SeqCst);
+            let mut gets = 0u64;
+            while !stop.load(Relaxed) {
+                // NOTE: The affinity is changed behind the back of the thread, which unpins
+                // it. Pin it again so that the next `get` takes the pinned path.
+                let _ = pin_current_thread(gets as usize % cpus);
+                let value = atomic.get();
+                assert_ne!(*value.0, u64::MAX, "read of a dropped value");
+                gets += 1;
+            }
+            unpin_current_thread();
+            assert!(gets > 0);
+        });
+        // Moves the reader to another cpu while it is running.
+        s.spawn(|| {
+            let mut cpu = 0;
+            while !stop.load(Relaxed) {
+                let tid = reader_tid.load(SeqCst);
+                if tid != 0 {
+                    move_to(tid, cpu);
+                    cpu = (cpu + 1) % cpus;
+                }
+                thread::yield_now();
+            }
+        });
+        s.spawn(|| {
+            let mut i = 1;
+            while Instant::now() < deadline {
+                atomic.set(Value::new(i));
+                i += 1;
+                if i % 64 == 0 {
+                    thread::yield_now();
+                }
+            }
+            stop.store(true, Relaxed);
+        });
+    });
+    drop(atomic);
+    // Perform the releases that have been deferred to other cpus.
+    shutdown();
+    assert_eq!(LIVE.load(SeqCst), 0, "values leaked");
+    assert_eq!(live_per_cpu_objects(), 0, "per-cpu objects leaked");
+}
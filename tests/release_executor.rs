@@ -0,0 +1,72 @@
+//! Installs a custom `ReleaseExecutor` and checks that the tasks of the crate run on it instead
+//! of the default helper threads.
+
+use {
+    lazy_atomic::{set_release_executor, shutdown, AtomicNmt, GcTask, ReleaseExecutor},
+    std::{
+        mem,
+        sync::atomic::{
+            AtomicBool, AtomicUsize,
+            Ordering::{Relaxed, SeqCst},
+        },
+        thread,
+    },
+};
+
+/// The number of tasks that have been sent to `Executor`.
+static TASKS: AtomicUsize = AtomicUsize::new(0);
+/// Whether `Executor::shutdown` has been called.
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Runs each task on a new thread that is pinned to the cpu of the task.
+struct Executor;
+
+impl ReleaseExecutor for Executor {
+    fn run_on_cpu(&self, cpu: usize, task: GcTask) {
+        TASKS.fetch_add(1, SeqCst);
+        thread::spawn(move || {
+            unsafe {
+                let mut set = mem::zeroed();
+                libc::CPU_SET(cpu, &mut set);
+                libc::sched_setaffinity(0, mem::size_of_val(&set), &set);
+            }
+            task();
+        });
+    }
+
+    fn shutdown(&self) {
+        SHUT_DOWN.store(true, Relaxed);
+    }
+}
+
+#[test]
+fn release_executor() {
+    assert!(set_release_executor(Box::new(Executor)).is_ok());
+    assert!(set_release_executor(Box::new(Executor)).is_err());
+
+    let cpus = thread::available_parallelism().unwrap().get();
+    let atomic = AtomicNmt::new(1);
+    assert_eq!(atomic.get(), 1);
+    // Reading the reference counts runs a task on every cpu.
+    let report = atomic.inspect();
+    assert_eq!(TASKS.load(SeqCst), cpus);
+    assert!(report.per_cpu.iter().any(|cpu| cpu.refcount.is_some()));
+    // No helper thread has been spawned.
+    let helpers = thread_names()
+        .into_iter()
+        .filter(|name| name.starts_with("la-per-cpu"))
+        .count();
+    assert_eq!(helpers, 0);
+
+    shutdown();
+    assert!(SHUT_DOWN.load(Relaxed));
+}
+
+/// Returns the names of the threads of this process.
+fn thread_names() -> Vec<String> {
+    std::fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+        .map(|name| name.trim().to_string())
+        .collect()
+}
@@ -0,0 +1,80 @@
+//! Reads a fake sysfs tree with missing files and cpu lists that name cpus that do not exist.
+
+use {
+    lazy_atomic::{
+        topology::{Granularity, Topology},
+        AtomicNmt,
+    },
+    std::{env, fs, path::Path, process, sync::Arc},
+};
+
+/// Writes `contents` to `path` relative to `root`, creating its directory.
+fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn topology_fixture() {
+    let root = env::temp_dir().join(format!("lazy-atomic-topology-{}", process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let cpu = "devices/system/cpu";
+    write(&root, &format!("{}/online", cpu), "0-7\n");
+    // cpus 0 and 1 are hyperthreads of the same core.
+    write(
+        &root,
+        &format!("{}/cpu0/topology/core_cpus_list", cpu),
+        "0-1\n",
+    );
+    write(
+        &root,
+        &format!("{}/cpu1/topology/core_cpus_list", cpu),
+        "0-1\n",
+    );
+    // cpu 2 has no topology directory, in particular neither `core_cpus_list` nor
+    // `cluster_cpus`.
+    fs::create_dir_all(root.join(format!("{}/cpu2", cpu))).unwrap();
+    // The core of cpu 3 starts at a cpu that is not online.
+    write(
+        &root,
+        &format!("{}/cpu3/topology/core_cpus_list", cpu),
+        "64-65\n",
+    );
+    // The cache of cpu 0 is shared with cpus 6 and 7 but not with cpu 0 itself.
+    let cache = format!("{}/cpu0/cache/index3", cpu);
+    write(&root, &format!("{}/type", cache), "Unified\n");
+    write(&root, &format!("{}/level", cache), "3\n");
+    write(&root, &format!("{}/shared_cpu_list", cache), "6-7\n");
+    // cpus 0 and 1 are on node 0.
+    fs::create_dir_all(root.join(format!("{}/cpu0/node0", cpu))).unwrap();
+    fs::create_dir_all(root.join(format!("{}/cpu1/node0", cpu))).unwrap();
+    write(&root, "devices/system/node/node0/cpulist", "0-1\n");
+
+    let topology = Topology::from_sysfs(&root).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    let shards = |granularity| {
+        (0..topology.num_cpus())
+            .map(|cpu| topology.shard(cpu, granularity))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(topology.num_cpus(), 8);
+    assert_eq!(shards(Granularity::Core), [0, 0, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(shards(Granularity::Llc), [6, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(shards(Granularity::NumaNode), [0, 0, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(topology.numa_node(0), Some(0));
+    assert_eq!(topology.numa_node(2), None);
+    assert_eq!(topology.shard(100, Granularity::Core), 100);
+
+    // The fake topology might describe more cpus than this system has. Shards whose leader
+    // does not exist here are not shared.
+    let topology = topology.into();
+    for granularity in [Granularity::Core, Granularity::Llc, Granularity::NumaNode] {
+        let atomic = AtomicNmt::builder()
+            .granularity(granularity)
+            .topology(Arc::clone(&topology))
+            .build(1);
+        atomic.set(2);
+        assert_eq!(atomic.get(), 2);
+    }
+}